@@ -77,10 +77,7 @@ fn redraw(sw_window: &SwWindow, waiting_next_image: &mut bool) {
     }
 
     if let Some(image_index) = sw_window.poll_next_image() {
-        paint_image(
-            &mut sw_window.lock_image(image_index),
-            sw_window.image_info(),
-        );
+        paint_image(sw_window.lock_image_view(image_index).view_mut::<u32>());
 
         sw_window.present_image(image_index);
     } else {
@@ -89,7 +86,7 @@ fn redraw(sw_window: &SwWindow, waiting_next_image: &mut bool) {
     }
 }
 
-fn paint_image(pixels: &mut [u8], image_info: swsurface::ImageInfo) {
+fn paint_image(mut image: swsurface::ImageViewMut<'_>) {
     use std::{num::Wrapping, time::Instant};
 
     const TABLE_SIZE: usize = 256;
@@ -107,10 +104,7 @@ fn paint_image(pixels: &mut [u8], image_info: swsurface::ImageInfo) {
 
     let t = Wrapping((T.elapsed().as_millis() * 20) as u32);
 
-    let [size_w, size_h] = image_info.extent;
-    for y in 0..size_h as usize {
-        let row = pixels[y * image_info.stride..][..size_w as usize * 4].chunks_exact_mut(4);
-
+    for (y, row) in image.rows_mut().enumerate() {
         let mut phases = [
             Wrapping((y * 165) as u32),
             Wrapping((y * 17) as u32),
@@ -126,7 +120,7 @@ fn paint_image(pixels: &mut [u8], image_info: swsurface::ImageInfo) {
             *x += t;
         }
 
-        for (x, p) in row.enumerate() {
+        for (x, p) in row.iter_mut().enumerate() {
             const FAC1: Wrapping<u32> = Wrapping(256);
             const FAC2: Wrapping<u32> = Wrapping(2);
 
@@ -140,10 +134,10 @@ fn paint_image(pixels: &mut [u8], image_info: swsurface::ImageInfo) {
             let mask2 = 0u8.wrapping_sub((x as u8 >> 3).wrapping_add(y as u8 >> 2) & 1);
             let mask1 = 0u8.wrapping_sub((x as u8 >> 2).wrapping_add(y as u8 >> 2) & 1) & !mask2;
 
-            p[0] = (val2.0 / 2) as u8 & mask2; // B
-            p[1] = (val3.0 / 2) as u8 & mask1; // G
-            p[2] = (val1.0 / 2) as u8 & !mask2; // R
-            p[3] = 255;
+            let b = (val2.0 / 2) as u8 & mask2;
+            let g = (val3.0 / 2) as u8 & mask1;
+            let r = (val1.0 / 2) as u8 & !mask2;
+            *p = 0xff000000 | (r as u32) << 16 | (g as u32) << 8 | b as u32;
 
             phases[0] += Wrapping(57);
             phases[1] += Wrapping(70);
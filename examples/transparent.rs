@@ -54,7 +54,7 @@ fn main() {
     event_loop.run(move |event, _, control_flow| {
         let encourage_teleport = is_wnd_partially_escaping(sw_window.window());
 
-        let (action, needs_redraw) = state.update(&assets, encourage_teleport);
+        let (action, needs_redraw) = state.update(assets, encourage_teleport);
 
         match action {
             None => {}
@@ -79,17 +79,15 @@ fn main() {
         match event {
             Event::WindowEvent { event, .. } => match event {
                 WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
-                WindowEvent::Resized(_)  => {
+                WindowEvent::Resized(_) => {
                     sw_window.update_surface_to_fit(FORMAT);
                     redraw(&sw_window, &state);
                 }
 
                 _ => {}
             },
-            Event::RedrawRequested(id) => {
-                if sw_window.window().id() == id {
-                    redraw(&sw_window, &state)
-                };
+            Event::RedrawRequested(id) if sw_window.window().id() == id => {
+                redraw(&sw_window, &state);
             }
             Event::UserEvent(_) => {
                 redraw(&sw_window, &state);
@@ -338,7 +336,7 @@ impl<'a> PlaybackState<'a> {
         let t = self.timer.elapsed();
         let mut updated = false;
 
-        while self.frames.len() > 0 {
+        while !self.frames.is_empty() {
             let frame_dur = Duration::from_millis(self.frames[0].delay().to_integer() as _);
             let frame_end = self.frame_start + frame_dur;
             if t < frame_end {
@@ -35,7 +35,7 @@ mod tests {
         assert!(Align::new(4).is_ok());
         assert!(Align::new(0).is_err());
         assert!(Align::new(3).is_err());
-        assert!(Align::new(usize::max_value()).is_err());
+        assert!(Align::new(usize::MAX).is_err());
     }
 
     #[test]
@@ -47,7 +47,7 @@ mod tests {
         assert_eq!(a1.align_up(2), Some(2));
         assert_eq!(a1.align_up(3), Some(3));
         assert_eq!(a1.align_up(4), Some(4));
-        assert_eq!(a1.align_up(usize::max_value()), Some(usize::max_value()));
+        assert_eq!(a1.align_up(usize::MAX), Some(usize::MAX));
 
         let a4 = Align::new(4).unwrap();
 
@@ -59,24 +59,12 @@ mod tests {
         assert_eq!(a4.align_up(5), Some(8));
         assert_eq!(a4.align_up(6), Some(8));
         assert_eq!(a4.align_up(7), Some(8));
-        assert_eq!(
-            a4.align_up(usize::max_value() - 6),
-            Some(usize::max_value() - 3)
-        );
-        assert_eq!(
-            a4.align_up(usize::max_value() - 5),
-            Some(usize::max_value() - 3)
-        );
-        assert_eq!(
-            a4.align_up(usize::max_value() - 4),
-            Some(usize::max_value() - 3)
-        );
-        assert_eq!(
-            a4.align_up(usize::max_value() - 3),
-            Some(usize::max_value() - 3)
-        );
-        assert_eq!(a4.align_up(usize::max_value() - 2), None);
-        assert_eq!(a4.align_up(usize::max_value() - 1), None);
-        assert_eq!(a4.align_up(usize::max_value()), None);
+        assert_eq!(a4.align_up(usize::MAX - 6), Some(usize::MAX - 3));
+        assert_eq!(a4.align_up(usize::MAX - 5), Some(usize::MAX - 3));
+        assert_eq!(a4.align_up(usize::MAX - 4), Some(usize::MAX - 3));
+        assert_eq!(a4.align_up(usize::MAX - 3), Some(usize::MAX - 3));
+        assert_eq!(a4.align_up(usize::MAX - 2), None);
+        assert_eq!(a4.align_up(usize::MAX - 1), None);
+        assert_eq!(a4.align_up(usize::MAX), None);
     }
 }
@@ -1,9 +1,11 @@
 use std::{
-    alloc::{alloc, dealloc, handle_alloc_error, realloc, Layout, LayoutErr},
+    alloc::{alloc, alloc_zeroed, dealloc, handle_alloc_error, realloc, Layout, LayoutError},
     ptr::NonNull,
     slice::{from_raw_parts, from_raw_parts_mut},
 };
 
+use super::SurfaceError;
+
 #[derive(Debug)]
 pub struct Buffer {
     ptr: NonNull<u8>,
@@ -24,32 +26,45 @@ impl Buffer {
         Self { ptr, layout }
     }
 
-    pub fn from_size_align(size: usize, align: usize) -> Result<Self, LayoutErr> {
+    pub fn from_size_align(size: usize, align: usize) -> Result<Self, LayoutError> {
         Layout::from_size_align(size, align).map(Self::new)
     }
 
-    pub fn resize(&mut self, new_size: usize) {
-        let new_layout = Layout::from_size_align(new_size, self.layout.align()).unwrap();
+    /// Allocate a zero-filled buffer having the same alignment as `self`.
+    /// Unlike `new`, this method reports an allocation failure instead of
+    /// aborting the process.
+    pub fn try_new_like(&self, size: usize) -> Result<Self, SurfaceError> {
+        let layout = Layout::from_size_align(size, self.layout.align())
+            .map_err(|_| SurfaceError::ExtentTooLarge)?;
+
+        let ptr = NonNull::new(unsafe { alloc_zeroed(layout) }).ok_or(SurfaceError::OutOfMemory)?;
+
+        Ok(Self { ptr, layout })
+    }
+
+    /// Resize the buffer. Unlike `new`, this method reports an allocation
+    /// failure instead of aborting the process. The buffer is left intact on
+    /// failure.
+    pub fn resize(&mut self, new_size: usize) -> Result<(), SurfaceError> {
+        let new_layout = Layout::from_size_align(new_size, self.layout.align())
+            .map_err(|_| SurfaceError::ExtentTooLarge)?;
 
         let new_ptr = unsafe { realloc(self.ptr.as_ptr(), self.layout, new_layout.size()) };
 
-        let ptr = if let Some(ptr) = NonNull::new(new_ptr) {
-            ptr
-        } else {
-            // Abort the process
-            handle_alloc_error(new_layout);
-        };
+        let ptr = NonNull::new(new_ptr).ok_or(SurfaceError::OutOfMemory)?;
 
         if new_layout.size() > self.layout.size() {
             unsafe {
                 ptr.as_ptr()
-                    .offset(self.layout.size() as isize)
+                    .add(self.layout.size())
                     .write_bytes(0, new_layout.size() - self.layout.size())
             };
         }
 
         self.ptr = ptr;
         self.layout = new_layout;
+
+        Ok(())
     }
 }
 
@@ -12,13 +12,17 @@ use cocoa::{
 use owning_ref::OwningRefMut;
 use std::{
     cell::{Cell, RefCell},
-    ops::{Deref, DerefMut},
+    ops::DerefMut,
 };
 use winit::{platform::macos::WindowExtMacOS, window::Window};
 
 use super::{
-    align::Align, buffer::Buffer, cglffi as gl, objcutils::IdRef, Config, Format, ImageInfo,
-    NullContextImpl,
+    align::Align,
+    buffer::Buffer,
+    cglffi as gl, clip_damage,
+    feedback::{FeedbackQueue, PresentationFeedback},
+    objcutils::IdRef,
+    Config, Format, ImageInfo, NullContextImpl, Rect, SurfaceError,
 };
 
 #[derive(Debug)]
@@ -28,10 +32,18 @@ pub struct SurfaceImpl {
     image: RefCell<Buffer>,
     image_info: Cell<ImageInfo>,
     scanline_align: Align,
+    /// `true` if the texture has to be updated entirely regardless of a
+    /// damage region, e.g., because it was just reallocated.
+    needs_full_update: Cell<bool>,
+    feedback: FeedbackQueue,
 }
 
 impl SurfaceImpl {
-    pub(crate) unsafe fn new(window: &Window, _: &NullContextImpl, config: &Config) -> Self {
+    pub(crate) unsafe fn new(
+        window: &Window,
+        _: &NullContextImpl,
+        config: &Config,
+    ) -> Result<Self, SurfaceError> {
         let scanline_align = Align::new(config.scanline_align).unwrap();
 
         // Create `NSOpenGLPixelFormat`
@@ -48,14 +60,14 @@ impl SurfaceImpl {
         ];
         let pixel_format = IdRef::new(NSOpenGLPixelFormat::alloc(nil).initWithAttributes_(&attrs))
             .non_nil()
-            .expect("no available pixel format");
+            .ok_or_else(|| SurfaceError::Platform("no available pixel format".to_owned()))?;
 
         // Create `NSOpenGLContext`.
         let gl_context = IdRef::new(
             NSOpenGLContext::alloc(nil).initWithFormat_shareContext_(*pixel_format, nil),
         )
         .non_nil()
-        .expect("could not create a OpenGL context");
+        .ok_or_else(|| SurfaceError::Platform("could not create a OpenGL context".to_owned()))?;
 
         gl_context.setView_(window.ns_view() as id);
 
@@ -77,37 +89,60 @@ impl SurfaceImpl {
         let mut gl_tex: gl::GLuint = 0;
         gl::glGenTextures(1, &mut gl_tex);
 
-        Self {
+        Ok(Self {
             gl_context,
             gl_tex,
             image: RefCell::new(Buffer::from_size_align(1, config.align).unwrap()),
             image_info: Cell::new(ImageInfo::default()),
             scanline_align,
-        }
+            needs_full_update: Cell::new(true),
+            feedback: FeedbackQueue::new(),
+        })
     }
 
-    pub fn update_surface(&self, extent: [u32; 2], format: Format) {
-        assert_ne!(extent[0], 0);
-        assert_ne!(extent[1], 0);
-        assert!(extent[0] <= <i32>::max_value() as u32);
-        assert!(extent[1] <= <i32>::max_value() as u32);
+    pub fn update_surface(
+        &self,
+        extent: [u32; 2],
+        format: Format,
+        scale: u32,
+    ) -> Result<(), SurfaceError> {
+        if extent[0] == 0 || extent[1] == 0 {
+            return Err(SurfaceError::ZeroExtent);
+        }
+        if extent[0] > i32::MAX as u32 || extent[1] > i32::MAX as u32 {
+            return Err(SurfaceError::ExtentTooLarge);
+        }
 
         use std::convert::TryInto;
         let extent_usize: [usize; 2] = [
-            extent[0].try_into().expect("overflow"),
-            extent[1].try_into().expect("overflow"),
+            extent[0]
+                .try_into()
+                .map_err(|_| SurfaceError::ExtentTooLarge)?,
+            extent[1]
+                .try_into()
+                .map_err(|_| SurfaceError::ExtentTooLarge)?,
         ];
 
         let stride = extent_usize[0]
             .checked_mul(4)
             .and_then(|x| self.scanline_align.align_up(x))
-            .expect("overflow");
+            .ok_or(SurfaceError::ExtentTooLarge)?;
 
-        let size = stride.checked_mul(extent_usize[1]).expect("overflow");
+        let size = stride
+            .checked_mul(extent_usize[1])
+            .ok_or(SurfaceError::ExtentTooLarge)?;
 
         let (ifmt, fmt, ty) = translate_format(format);
 
-        let mut image = self.image.borrow_mut();
+        let mut image = self
+            .image
+            .try_borrow_mut()
+            .map_err(|_| SurfaceError::ImageLocked)?;
+
+        // Allocate the memory first so that a failure doesn't leave the
+        // texture and the buffer in an inconsistent state
+        image.resize(size)?;
+
         let gl_context = &self.gl_context;
         unsafe {
             // Because the window was resized...
@@ -132,15 +167,17 @@ impl SurfaceImpl {
 
             gl::glTexParameteri(gl::GL_TEXTURE_2D, gl::GL_TEXTURE_MAG_FILTER, gl::GL_LINEAR);
             gl::glTexParameteri(gl::GL_TEXTURE_2D, gl::GL_TEXTURE_MIN_FILTER, gl::GL_LINEAR);
-
-            image.resize(size);
         }
 
         self.image_info.set(ImageInfo {
             extent,
             stride,
             format,
+            scale,
         });
+        self.needs_full_update.set(true);
+
+        Ok(())
     }
 
     pub fn supported_formats(&self) -> impl Iterator<Item = Format> + '_ {
@@ -159,43 +196,83 @@ impl SurfaceImpl {
         true
     }
 
-    pub fn poll_next_image(&self) -> Option<usize> {
+    pub fn honors_scale(&self) -> bool {
+        false
+    }
+
+    pub fn poll_next_image(&self, filter: &dyn Fn(usize) -> bool) -> Option<usize> {
         // `present_image` will block instead, unfortunately.
-        Some(0)
+        Some(0).filter(|&i| filter(i))
+    }
+
+    pub fn lock_image(&self, i: usize) -> Result<impl DerefMut<Target = [u8]> + '_, SurfaceError> {
+        if i != 0 {
+            return Err(SurfaceError::BadImageIndex(i));
+        }
+        let image = self
+            .image
+            .try_borrow_mut()
+            .map_err(|_| SurfaceError::ImageLocked)?;
+        Ok(OwningRefMut::new(image).map_mut(|p| &mut **p))
+    }
+
+    pub fn set_destination_size(&self, _size: Option<[u32; 2]>) -> Result<(), SurfaceError> {
+        Err(SurfaceError::BackendUnavailable("`set_destination_size`"))
     }
 
-    pub fn lock_image(&self, i: usize) -> impl Deref<Target = [u8]> + DerefMut + '_ {
-        assert_eq!(i, 0);
-        OwningRefMut::new(self.image.borrow_mut()).map_mut(|p| &mut **p)
+    pub fn set_opaque_region(&self, _region: &[Rect]) {}
+
+    pub fn poll_presentation_feedback(&self) -> Option<PresentationFeedback> {
+        self.feedback.pop()
     }
 
-    pub fn present_image(&self, i: usize) {
-        assert_eq!(i, 0);
+    pub fn present_image(&self, i: usize, damage: Option<&[Rect]>) -> Result<(), SurfaceError> {
+        if i != 0 {
+            return Err(SurfaceError::BadImageIndex(i));
+        }
 
         let gl_context = &self.gl_context;
         let image_info = self.image_info.get();
         let image = self
             .image
             .try_borrow()
-            .expect("the image is currently locked");
+            .map_err(|_| SurfaceError::ImageLocked)?;
+
+        if image_info.extent[0] == 0 {
+            return Err(SurfaceError::NotInitialized);
+        }
+
         let (_ifmt, fmt, ty) = translate_format(image_info.format);
 
+        // The texture contents are undefined after reallocation
+        let damage = if self.needs_full_update.replace(false) {
+            None
+        } else {
+            damage
+        };
+
         unsafe {
             gl_context.makeCurrentContext();
             gl::glBindTexture(gl::GL_TEXTURE_2D, self.gl_tex);
 
+            // Only upload the damaged regions. The texture retains the
+            // contents of the previous frames.
             gl::glPixelStorei(gl::GL_UNPACK_ROW_LENGTH, (image_info.stride / 4) as _);
-            gl::glTexSubImage2D(
-                gl::GL_TEXTURE_2D,
-                0,
-                0,
-                0,
-                image_info.extent[0] as _,
-                image_info.extent[1] as _,
-                fmt,
-                ty,
-                image.as_ptr() as *const _,
-            );
+            for rect in clip_damage(damage, image_info.extent) {
+                let offset =
+                    rect.origin[1] as usize * image_info.stride + rect.origin[0] as usize * 4;
+                gl::glTexSubImage2D(
+                    gl::GL_TEXTURE_2D,
+                    0,
+                    rect.origin[0] as _,
+                    rect.origin[1] as _,
+                    rect.extent[0] as _,
+                    rect.extent[1] as _,
+                    fmt,
+                    ty,
+                    image[offset..].as_ptr() as *const _,
+                );
+            }
             gl::glPixelStorei(gl::GL_UNPACK_ROW_LENGTH, 0);
 
             gl::glClearColor(0.0, 0.0, 0.0, 0.0);
@@ -215,6 +292,10 @@ impl SurfaceImpl {
             // actual blocking occurs
             gl_context.flushBuffer();
         }
+
+        self.feedback.push_estimated(i);
+
+        Ok(())
     }
 }
 
@@ -222,5 +303,7 @@ fn translate_format(format: Format) -> (gl::GLenum, gl::GLenum, gl::GLenum) {
     match format {
         Format::Argb8888 => (gl::GL_RGBA, gl::GL_BGRA, gl::GL_UNSIGNED_BYTE),
         Format::Xrgb8888 => (gl::GL_RGB, gl::GL_BGRA, gl::GL_UNSIGNED_INT_8_8_8_8_REV),
+        // Rejected by `Surface::update_surface`
+        _ => unreachable!(),
     }
 }
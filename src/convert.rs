@@ -0,0 +1,700 @@
+//! Pixel format conversion from common buffer layouts into swapchain images
+//!
+//! This module copies pixels produced by third-party renderers and image
+//! decoders into a swapchain image of any [`Format`]. Sources with straight
+//! (non-premultiplied) alpha are premultiplied when written to a format having
+//! an alpha channel. For a format without an alpha channel, the alpha channel
+//! of the source is ignored.
+//!
+//! [`crate::Surface::write_from`] wraps [`convert`] for the common case of
+//! writing into a locked swapchain image.
+//!
+//! [`premultiply_in_place`] and [`unpremultiply_in_place`] convert the alpha
+//! representation of an image that has already been drawn.
+use super::{Format, ImageInfo, Rect, SurfaceError};
+
+/// The pixel layout of a source buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SourceFormat {
+    /// 32-bit RGBA format with straight alpha. Each pixel is stored as the
+    /// bytes `[r, g, b, a]`.
+    Rgba8,
+    /// 32-bit BGRA format with straight alpha. Each pixel is stored as the
+    /// bytes `[b, g, r, a]`.
+    Bgra8,
+    /// 24-bit RGB format. Each pixel is stored as the bytes `[r, g, b]`.
+    Rgb8,
+    /// 8-bit grayscale format.
+    Gray8,
+}
+
+impl SourceFormat {
+    /// Get the size of a pixel, measured in bytes.
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            SourceFormat::Rgba8 | SourceFormat::Bgra8 => 4,
+            SourceFormat::Rgb8 => 3,
+            SourceFormat::Gray8 => 1,
+        }
+    }
+}
+
+/// Describes the layout of a source buffer passed to [`convert`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SourceLayout {
+    /// The pixel format.
+    pub format: SourceFormat,
+    /// The image size (`[width, height]`), measured in pixels.
+    pub extent: [u32; 2],
+    /// The offset between rows, measured in bytes.
+    pub stride: usize,
+}
+
+impl SourceLayout {
+    /// Construct a `SourceLayout` for a tightly packed image, i.e., one
+    /// without padding between rows.
+    pub fn new(format: SourceFormat, extent: [u32; 2]) -> Self {
+        Self {
+            format,
+            extent,
+            stride: extent[0] as usize * format.bytes_per_pixel(),
+        }
+    }
+
+    /// Get the minimum length of a buffer having this layout, measured in
+    /// bytes. The last row doesn't have to be padded to `stride`.
+    ///
+    /// Returns `None` if the length doesn't fit in `usize`.
+    pub fn min_len(&self) -> Option<usize> {
+        if self.extent[0] == 0 || self.extent[1] == 0 {
+            Some(0)
+        } else {
+            (self.extent[1] as usize - 1)
+                .checked_mul(self.stride)?
+                .checked_add((self.extent[0] as usize).checked_mul(self.format.bytes_per_pixel())?)
+        }
+    }
+}
+
+/// Copy the top-left `dst_rect.extent` pixels of `src` into the region
+/// `dst_rect` of `dst`, converting them to `dst_info.format`.
+///
+/// `dst` is the contents of a swapchain image described by `dst_info`, such
+/// as the slice returned by [`crate::Surface::lock_image`].
+///
+/// Panics if `dst_rect` doesn't fit in `dst_info.extent`, the source image is
+/// smaller than `dst_rect.extent`, or either of the buffers is too short.
+pub fn convert(
+    src: &[u8],
+    src_layout: &SourceLayout,
+    dst: &mut [u8],
+    dst_info: &ImageInfo,
+    dst_rect: Rect,
+) {
+    let Rect { origin, extent } = dst_rect;
+    assert!(
+        rect_fits(dst_rect, dst_info),
+        "destination rectangle out of bounds"
+    );
+    assert!(
+        extent[0] <= src_layout.extent[0] && extent[1] <= src_layout.extent[1],
+        "source image is smaller than the destination rectangle"
+    );
+    assert!(
+        src_layout.min_len().map_or(false, |len| src.len() >= len),
+        "source buffer is too short"
+    );
+
+    if dst_rect.is_empty() {
+        return;
+    }
+
+    let dst_bpp = dst_info.format.bytes_per_pixel();
+    let dst_end = (origin[1] + extent[1] - 1) as usize * dst_info.stride
+        + (origin[0] + extent[0]) as usize * dst_bpp;
+    assert!(dst.len() >= dst_end, "destination buffer is too short");
+
+    let rows = Rows {
+        src,
+        src_stride: src_layout.stride,
+        dst,
+        dst_stride: dst_info.stride,
+        rect: dst_rect,
+    };
+
+    match src_layout.format {
+        SourceFormat::Rgba8 => convert_from(rows, read_rgba8, 4, dst_info.format),
+        SourceFormat::Bgra8 => convert_from(rows, read_bgra8, 4, dst_info.format),
+        SourceFormat::Rgb8 => convert_from(rows, read_rgb8, 3, dst_info.format),
+        SourceFormat::Gray8 => convert_from(rows, read_gray8, 1, dst_info.format),
+    }
+}
+
+/// Check the parameters of `convert` that would cause it to panic, except for
+/// the length of the destination buffer.
+pub(crate) fn validate(
+    src: &[u8],
+    src_layout: &SourceLayout,
+    dst_info: &ImageInfo,
+    dst_rect: Rect,
+) -> Result<(), SurfaceError> {
+    if !rect_fits(dst_rect, dst_info) {
+        return Err(SurfaceError::BadRect(dst_rect));
+    }
+    if dst_rect.extent[0] > src_layout.extent[0]
+        || dst_rect.extent[1] > src_layout.extent[1]
+        || src_layout.min_len().map_or(true, |len| src.len() < len)
+    {
+        return Err(SurfaceError::BadSource);
+    }
+    Ok(())
+}
+
+/// Convert the pixels in `rect` of `image` from straight alpha to
+/// pre-multiplied alpha.
+///
+/// `image` is the contents of a swapchain image described by `image_info`.
+/// Does nothing if `image_info.format` doesn't have an alpha channel.
+///
+/// Panics if `rect` doesn't fit in `image_info.extent` or `image` is too
+/// short.
+pub fn premultiply_in_place(image: &mut [u8], image_info: &ImageInfo, rect: Rect) {
+    let layout = match AlphaLayout::new(image_info.format) {
+        Some(layout) => layout,
+        None => return,
+    };
+    for_each_pixel(image, image_info, rect, |p| {
+        let a = layout.alpha(*p);
+        *p = layout.map_colors(*p, |c| (c * a + layout.alpha_max / 2) / layout.alpha_max);
+    });
+}
+
+/// Convert the pixels in `rect` of `image` from pre-multiplied alpha to
+/// straight alpha. The color channels of fully transparent pixels are set to
+/// zero.
+///
+/// This is the inverse of [`premultiply_in_place`] except for the precision
+/// lost by pre-multiplication. See it for the parameters.
+pub fn unpremultiply_in_place(image: &mut [u8], image_info: &ImageInfo, rect: Rect) {
+    let layout = match AlphaLayout::new(image_info.format) {
+        Some(layout) => layout,
+        None => return,
+    };
+    for_each_pixel(image, image_info, rect, |p| {
+        let a = layout.alpha(*p);
+        *p = layout.map_colors(*p, |c| {
+            (c * layout.alpha_max + a / 2)
+                .checked_div(a)
+                .map_or(0, |c| c.min(layout.color_max))
+        });
+    });
+}
+
+/// Find pixels in `rect` of `image` having a color channel greater than the
+/// alpha channel, which is invalid in pre-multiplied alpha. If `clamp` is
+/// `true`, such channels are clamped to the alpha value.
+///
+/// Returns the number of invalid pixels and the position of the first one.
+pub(crate) fn find_invalid_premultiplied(
+    image: &mut [u8],
+    image_info: &ImageInfo,
+    rect: Rect,
+    clamp: bool,
+) -> (usize, Option<[u32; 2]>) {
+    let layout = match AlphaLayout::new(image_info.format) {
+        Some(layout) => layout,
+        None => return (0, None),
+    };
+    let mut count = 0;
+    let mut first = None;
+    let mut i = 0;
+    for_each_pixel(image, image_info, rect, |p| {
+        let a = layout.alpha(*p);
+        let max = a * layout.color_max / layout.alpha_max;
+        let clamped = layout.map_colors(*p, |c| c.min(max));
+        if clamped != *p {
+            count += 1;
+            let width = rect.extent[0] as usize;
+            first = first.or_else(|| {
+                Some([
+                    rect.origin[0] + (i % width) as u32,
+                    rect.origin[1] + (i / width) as u32,
+                ])
+            });
+            if clamp {
+                *p = clamped;
+            }
+        }
+        i += 1;
+    });
+    (count, first)
+}
+
+/// Multiply the color channel `c` by the alpha value `a`, rounding to the
+/// nearest integer.
+#[inline]
+pub(crate) fn premultiply(c: u8, a: u8) -> u8 {
+    let t = u32::from(c) * u32::from(a) + 128;
+    ((t + (t >> 8)) >> 8) as u8
+}
+
+/// Get a flag indicating whether `rect` fits in the image described by
+/// `image_info`.
+fn rect_fits(rect: Rect, image_info: &ImageInfo) -> bool {
+    let Rect { origin, extent } = rect;
+    origin[0]
+        .checked_add(extent[0])
+        .map_or(false, |x| x <= image_info.extent[0])
+        && origin[1]
+            .checked_add(extent[1])
+            .map_or(false, |y| y <= image_info.extent[1])
+}
+
+/// The bit layout of a 32-bit format with an alpha channel. The alpha channel
+/// occupies the topmost bits, followed by three color channels of the same
+/// width. The order of the color channels doesn't matter for alpha
+/// processing.
+#[derive(Debug, Clone, Copy)]
+struct AlphaLayout {
+    color_bits: u32,
+    color_max: u32,
+    alpha_max: u32,
+}
+
+impl AlphaLayout {
+    fn new(format: Format) -> Option<Self> {
+        let color_bits = match format {
+            Format::Argb8888 | Format::Abgr8888 => 8,
+            Format::Argb2101010 => 10,
+            Format::Xrgb8888 | Format::Xbgr8888 | Format::Rgb565 | Format::Xrgb2101010 => {
+                return None
+            }
+        };
+        Some(Self {
+            color_bits,
+            color_max: (1 << color_bits) - 1,
+            alpha_max: (1 << (32 - color_bits * 3)) - 1,
+        })
+    }
+
+    #[inline]
+    fn alpha(&self, pixel: u32) -> u32 {
+        pixel >> (self.color_bits * 3)
+    }
+
+    /// Apply `f` to each color channel of `pixel`, keeping the alpha channel.
+    #[inline]
+    fn map_colors(&self, pixel: u32, mut f: impl FnMut(u32) -> u32) -> u32 {
+        let mut out = pixel & !((1 << (self.color_bits * 3)) - 1);
+        for i in 0..3 {
+            let shift = self.color_bits * i;
+            out |= f(pixel >> shift & self.color_max) << shift;
+        }
+        out
+    }
+}
+
+/// Call `f` for each pixel in `rect` of a 32-bit image.
+fn for_each_pixel(
+    image: &mut [u8],
+    image_info: &ImageInfo,
+    rect: Rect,
+    mut f: impl FnMut(&mut u32),
+) {
+    assert!(rect_fits(rect, image_info), "rectangle out of bounds");
+    debug_assert_eq!(image_info.format.bytes_per_pixel(), 4);
+
+    let x_range = rect.origin[0] as usize * 4..(rect.origin[0] + rect.extent[0]) as usize * 4;
+    for y in rect.origin[1]..rect.origin[1] + rect.extent[1] {
+        let row = &mut image[y as usize * image_info.stride..][x_range.clone()];
+        for pixel in row.chunks_exact_mut(4) {
+            let mut value = u32::from_ne_bytes([pixel[0], pixel[1], pixel[2], pixel[3]]);
+            f(&mut value);
+            pixel.copy_from_slice(&value.to_ne_bytes());
+        }
+    }
+}
+
+/// The source and destination buffers of a conversion.
+struct Rows<'a> {
+    src: &'a [u8],
+    src_stride: usize,
+    dst: &'a mut [u8],
+    dst_stride: usize,
+    /// The destination region. The source region starts at `[0, 0]`.
+    rect: Rect,
+}
+
+/// Select a pixel writer for `format`. Each combination of a reader and a
+/// writer gets its own instantiation of `convert_rows` so that they can be
+/// inlined into the inner loop.
+fn convert_from(rows: Rows<'_>, read: impl Fn(&[u8]) -> [u8; 4], src_bpp: usize, format: Format) {
+    match format {
+        Format::Argb8888 => convert_rows(rows, read, src_bpp, write_argb8888, 4),
+        Format::Xrgb8888 => convert_rows(rows, read, src_bpp, write_xrgb8888, 4),
+        Format::Abgr8888 => convert_rows(rows, read, src_bpp, write_abgr8888, 4),
+        Format::Xbgr8888 => convert_rows(rows, read, src_bpp, write_xbgr8888, 4),
+        Format::Rgb565 => convert_rows(rows, read, src_bpp, write_rgb565, 2),
+        Format::Argb2101010 => convert_rows(rows, read, src_bpp, write_argb2101010, 4),
+        Format::Xrgb2101010 => convert_rows(rows, read, src_bpp, write_xrgb2101010, 4),
+    }
+}
+
+fn convert_rows(
+    rows: Rows<'_>,
+    read: impl Fn(&[u8]) -> [u8; 4],
+    src_bpp: usize,
+    write: impl Fn(&mut [u8], [u8; 4]),
+    dst_bpp: usize,
+) {
+    let Rows {
+        src,
+        src_stride,
+        dst,
+        dst_stride,
+        rect,
+    } = rows;
+    let [x, y] = [rect.origin[0] as usize, rect.origin[1] as usize];
+    let [width, height] = [rect.extent[0] as usize, rect.extent[1] as usize];
+
+    for row in 0..height {
+        let src_row = &src[row * src_stride..][..width * src_bpp];
+        let dst_row = &mut dst[(y + row) * dst_stride + x * dst_bpp..][..width * dst_bpp];
+
+        for (s, d) in src_row
+            .chunks_exact(src_bpp)
+            .zip(dst_row.chunks_exact_mut(dst_bpp))
+        {
+            write(d, read(s));
+        }
+    }
+}
+
+// Pixel readers. They return `[r, g, b, a]` with straight alpha.
+
+#[inline]
+fn read_rgba8(p: &[u8]) -> [u8; 4] {
+    [p[0], p[1], p[2], p[3]]
+}
+
+#[inline]
+fn read_bgra8(p: &[u8]) -> [u8; 4] {
+    [p[2], p[1], p[0], p[3]]
+}
+
+#[inline]
+fn read_rgb8(p: &[u8]) -> [u8; 4] {
+    [p[0], p[1], p[2], 0xff]
+}
+
+#[inline]
+fn read_gray8(p: &[u8]) -> [u8; 4] {
+    [p[0], p[0], p[0], 0xff]
+}
+
+// Pixel writers. They take `[r, g, b, a]` with straight alpha.
+
+#[inline]
+fn write_u32(p: &mut [u8], value: u32) {
+    p.copy_from_slice(&value.to_ne_bytes());
+}
+
+#[inline]
+fn write_argb8888(p: &mut [u8], [r, g, b, a]: [u8; 4]) {
+    let [r, g, b] = [premultiply(r, a), premultiply(g, a), premultiply(b, a)];
+    write_u32(p, u32::from_be_bytes([a, r, g, b]));
+}
+
+#[inline]
+fn write_xrgb8888(p: &mut [u8], [r, g, b, _]: [u8; 4]) {
+    write_u32(p, u32::from_be_bytes([0xff, r, g, b]));
+}
+
+#[inline]
+fn write_abgr8888(p: &mut [u8], [r, g, b, a]: [u8; 4]) {
+    let [r, g, b] = [premultiply(r, a), premultiply(g, a), premultiply(b, a)];
+    write_u32(p, u32::from_be_bytes([a, b, g, r]));
+}
+
+#[inline]
+fn write_xbgr8888(p: &mut [u8], [r, g, b, _]: [u8; 4]) {
+    write_u32(p, u32::from_be_bytes([0xff, b, g, r]));
+}
+
+#[inline]
+fn write_rgb565(p: &mut [u8], [r, g, b, _]: [u8; 4]) {
+    let value = (u16::from(r) >> 3) << 11 | (u16::from(g) >> 2) << 5 | u16::from(b) >> 3;
+    p.copy_from_slice(&value.to_ne_bytes());
+}
+
+/// Expand an 8-bit channel value to 10 bits.
+#[inline]
+fn expand_10(c: u8) -> u32 {
+    u32::from(c) << 2 | u32::from(c) >> 6
+}
+
+#[inline]
+fn write_argb2101010(p: &mut [u8], [r, g, b, a]: [u8; 4]) {
+    // Premultiply by the quantized alpha value so that no color channel
+    // exceeds the alpha channel
+    let a2 = (u32::from(a) + 42) / 85;
+    let a = (a2 * 85) as u8;
+    let [r, g, b] = [premultiply(r, a), premultiply(g, a), premultiply(b, a)];
+    write_u32(
+        p,
+        a2 << 30 | expand_10(r) << 20 | expand_10(g) << 10 | expand_10(b),
+    );
+}
+
+#[inline]
+fn write_xrgb2101010(p: &mut [u8], [r, g, b, _]: [u8; 4]) {
+    write_u32(
+        p,
+        0b11 << 30 | expand_10(r) << 20 | expand_10(g) << 10 | expand_10(b),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Config, Surface};
+    use super::*;
+
+    fn image_info(extent: [u32; 2], stride: usize, format: Format) -> ImageInfo {
+        ImageInfo {
+            extent,
+            stride,
+            format,
+            scale: 1,
+        }
+    }
+
+    fn words(bytes: &[u8]) -> Vec<u32> {
+        bytes
+            .chunks_exact(4)
+            .map(|p| u32::from_ne_bytes([p[0], p[1], p[2], p[3]]))
+            .collect()
+    }
+
+    #[test]
+    fn premultiply_rounds() {
+        for c in 0..=255u32 {
+            for a in 0..=255u32 {
+                let expected = ((c * a + 127) / 255) as u8;
+                assert_eq!(premultiply(c as u8, a as u8), expected, "{} * {}", c, a);
+            }
+        }
+    }
+
+    #[test]
+    fn rgba_to_argb8888() {
+        let src = [0xff, 0x80, 0x00, 0xff, 0xff, 0x80, 0x00, 0x80];
+        let layout = SourceLayout::new(SourceFormat::Rgba8, [2, 1]);
+        let mut dst = [0u8; 8];
+
+        convert(
+            &src,
+            &layout,
+            &mut dst,
+            &image_info([2, 1], 8, Format::Argb8888),
+            Rect::new([0, 0], [2, 1]),
+        );
+
+        assert_eq!(words(&dst), [0xffff8000, 0x80804000]);
+    }
+
+    #[test]
+    fn opaque_formats_ignore_alpha() {
+        let src = [0x10, 0x20, 0x30, 0x00];
+        let layout = SourceLayout::new(SourceFormat::Bgra8, [1, 1]);
+        let rect = Rect::new([0, 0], [1, 1]);
+        let mut dst = [0u8; 4];
+
+        convert(
+            &src,
+            &layout,
+            &mut dst,
+            &image_info([1, 1], 4, Format::Xrgb8888),
+            rect,
+        );
+        assert_eq!(words(&dst), [0xff302010]);
+
+        convert(
+            &src,
+            &layout,
+            &mut dst,
+            &image_info([1, 1], 4, Format::Xbgr8888),
+            rect,
+        );
+        assert_eq!(words(&dst), [0xff102030]);
+
+        convert(
+            &src,
+            &layout,
+            &mut dst,
+            &image_info([1, 1], 4, Format::Xrgb2101010),
+            rect,
+        );
+        assert_eq!(
+            words(&dst),
+            [0xc0000000 | 0x0c0 << 20 | 0x080 << 10 | 0x040]
+        );
+    }
+
+    #[test]
+    fn gray_to_rgb565() {
+        let src = [0xff, 0x00];
+        let layout = SourceLayout::new(SourceFormat::Gray8, [2, 1]);
+        let mut dst = [0u8; 4];
+
+        convert(
+            &src,
+            &layout,
+            &mut dst,
+            &image_info([2, 1], 4, Format::Rgb565),
+            Rect::new([0, 0], [2, 1]),
+        );
+
+        let pixels: Vec<_> = dst
+            .chunks_exact(2)
+            .map(|p| u16::from_ne_bytes([p[0], p[1]]))
+            .collect();
+        assert_eq!(pixels, [0xffff, 0x0000]);
+    }
+
+    #[test]
+    fn strides_and_offset() {
+        // 2x2 RGB image with 2 bytes of padding per row
+        let src = [1, 1, 1, 2, 2, 2, 0, 0, 3, 3, 3, 4, 4, 4];
+        let layout = SourceLayout {
+            format: SourceFormat::Rgb8,
+            extent: [2, 2],
+            stride: 8,
+        };
+        let mut dst = [0u8; 4 * 3 * 3];
+
+        convert(
+            &src,
+            &layout,
+            &mut dst,
+            &image_info([3, 3], 12, Format::Abgr8888),
+            Rect::new([1, 1], [2, 2]),
+        );
+
+        assert_eq!(
+            words(&dst),
+            [
+                0, 0, 0, //
+                0, 0xff010101, 0xff020202, //
+                0, 0xff030303, 0xff040404,
+            ]
+        );
+    }
+
+    #[test]
+    fn premultiply_round_trip() {
+        let mut image = Vec::new();
+        for &p in &[0x80ff4000u32, 0x00ffffff, 0xffabcdef, 0x40ff0000] {
+            image.extend_from_slice(&p.to_ne_bytes());
+        }
+        let info = image_info([2, 2], 8, Format::Argb8888);
+        let rect = Rect::new([0, 0], [2, 2]);
+
+        premultiply_in_place(&mut image, &info, rect);
+        assert_eq!(
+            words(&image),
+            [0x80802000, 0x00000000, 0xffabcdef, 0x40400000]
+        );
+
+        unpremultiply_in_place(&mut image, &info, rect);
+        assert_eq!(
+            words(&image),
+            [0x80ff4000, 0x00000000, 0xffabcdef, 0x40ff0000]
+        );
+    }
+
+    #[test]
+    fn premultiply_2101010() {
+        let mut image = (1 << 30 | 0x3ff << 20 | 0x300u32).to_ne_bytes();
+        let info = image_info([1, 1], 4, Format::Argb2101010);
+        let rect = Rect::new([0, 0], [1, 1]);
+
+        premultiply_in_place(&mut image, &info, rect);
+        assert_eq!(words(&image), [1 << 30 | 0x155 << 20 | 0x100]);
+
+        // Formats without an alpha channel are left untouched
+        let info = image_info([1, 1], 4, Format::Xrgb2101010);
+        premultiply_in_place(&mut image, &info, rect);
+        assert_eq!(words(&image), [1 << 30 | 0x155 << 20 | 0x100]);
+    }
+
+    #[test]
+    fn invalid_premultiplied() {
+        let mut image = Vec::new();
+        for &p in &[0x80804000u32, 0x80ff4000, 0x00000001, 0xffffffff] {
+            image.extend_from_slice(&p.to_ne_bytes());
+        }
+        let info = image_info([2, 2], 8, Format::Abgr8888);
+        let rect = Rect::new([0, 0], [2, 2]);
+
+        let result = find_invalid_premultiplied(&mut image, &info, rect, false);
+        assert_eq!(result, (2, Some([1, 0])));
+        assert_eq!(words(&image)[1], 0x80ff4000);
+
+        let result = find_invalid_premultiplied(&mut image, &info, rect, true);
+        assert_eq!(result, (2, Some([1, 0])));
+        assert_eq!(words(&image), [0x80804000, 0x80804000, 0, 0xffffffff]);
+
+        let result = find_invalid_premultiplied(&mut image, &info, rect, false);
+        assert_eq!(result, (0, None));
+    }
+
+    #[test]
+    #[should_panic(expected = "destination rectangle out of bounds")]
+    fn out_of_bounds() {
+        let layout = SourceLayout::new(SourceFormat::Gray8, [2, 2]);
+        convert(
+            &[0; 4],
+            &layout,
+            &mut [0; 16],
+            &image_info([2, 2], 8, Format::Argb8888),
+            Rect::new([1, 1], [2, 2]),
+        );
+    }
+
+    #[test]
+    fn write_from() {
+        let surface = Surface::new_headless([4, 4], &Config::default());
+        let i = surface.poll_next_image().unwrap();
+        let layout = SourceLayout::new(SourceFormat::Gray8, [2, 2]);
+
+        surface.write_from(i, &[0xff; 4], &layout, Rect::new([2, 2], [2, 2]));
+        assert_eq!(
+            surface.lock_image_view(i).view_mut::<u32>().pixel(3, 3),
+            0xffffffff
+        );
+
+        assert!(matches!(
+            surface.try_write_from(i, &[0; 4], &layout, Rect::new([3, 3], [2, 2])),
+            Err(SurfaceError::BadRect(_))
+        ));
+        assert!(matches!(
+            surface.try_write_from(i, &[0; 4], &layout, Rect::new([0, 0], [3, 2])),
+            Err(SurfaceError::BadSource)
+        ));
+        assert!(matches!(
+            surface.try_write_from(i, &[0; 3], &layout, Rect::new([0, 0], [2, 2])),
+            Err(SurfaceError::BadSource)
+        ));
+
+        // The buffer length implied by `stride` overflows
+        let layout = SourceLayout {
+            stride: usize::MAX,
+            ..layout
+        };
+        assert_eq!(layout.min_len(), None);
+        assert!(matches!(
+            surface.try_write_from(i, &[0; 4], &layout, Rect::new([0, 0], [2, 2])),
+            Err(SurfaceError::BadSource)
+        ));
+    }
+}
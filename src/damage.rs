@@ -0,0 +1,274 @@
+//! Per-swapchain-image damage tracking
+use super::{Format, ImageInfo, Rect, Surface, SwWindow};
+
+/// The maximum number of rectangles stored per swapchain image. Additional
+/// rectangles cause the region to be collapsed into its bounding box.
+const MAX_RECTS: usize = 8;
+
+/// Tracks the regions each swapchain image must repaint.
+///
+/// When [`Surface::does_preserve_image`] returns `true`, a swapchain image
+/// retains the contents from the last time it was rendered, which might be
+/// several frames behind the latest one if there are multiple swapchain
+/// images. This type keeps track of the regions that have changed since each
+/// image was rendered.
+///
+/// Each frame, the application should:
+///
+///  1. Call [`DamageTracker::update_for_surface`] (or one of its variants) to
+///     pick up the changes in the surface properties. The tracker resets
+///     itself if the image layout (size, stride, or pixel format) or the
+///     number of images has changed.
+///  2. Report the regions it is going to change in this frame by
+///     [`DamageTracker::add_damage`].
+///  3. Get the index `i` of the next image by `poll_next_image`, and repaint
+///     the regions returned by [`DamageTracker::image_damage`]`(i)`.
+///  4. Present the image (e.g., by [`Surface::present_image_with_damage`]
+///     passing the regions reported in step 2) and call
+///     [`DamageTracker::mark_painted`]`(i)`.
+#[derive(Debug, Clone, Default)]
+pub struct DamageTracker {
+    extent: [u32; 2],
+    /// The stride and the pixel format of the images. A backend may reallocate
+    /// the images when they change, so they reset the tracker as well.
+    layout: Option<(usize, Format)>,
+    /// The regions to repaint for each swapchain image.
+    images: Vec<Vec<Rect>>,
+}
+
+impl DamageTracker {
+    /// Construct a `DamageTracker`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Update the surface properties. If they are different from the last
+    /// ones, all images are marked as entirely damaged.
+    ///
+    /// If `does_preserve_image` is `false`, images are always entirely
+    /// damaged.
+    ///
+    /// This method only knows the image size. Prefer
+    /// [`DamageTracker::update_with_image_info`], which also detects changes
+    /// in the stride and the pixel format.
+    pub fn update(&mut self, extent: [u32; 2], num_images: usize, does_preserve_image: bool) {
+        self.update_inner(extent, self.layout, num_images, does_preserve_image);
+    }
+
+    /// Update the surface properties, including the layout described by
+    /// `image_info`.
+    ///
+    /// See [`DamageTracker::update`].
+    pub fn update_with_image_info(
+        &mut self,
+        image_info: &ImageInfo,
+        num_images: usize,
+        does_preserve_image: bool,
+    ) {
+        self.update_inner(
+            image_info.extent,
+            Some((image_info.stride, image_info.format)),
+            num_images,
+            does_preserve_image,
+        );
+    }
+
+    fn update_inner(
+        &mut self,
+        extent: [u32; 2],
+        layout: Option<(usize, Format)>,
+        num_images: usize,
+        does_preserve_image: bool,
+    ) {
+        if extent != self.extent
+            || layout != self.layout
+            || num_images != self.images.len()
+            || !does_preserve_image
+        {
+            self.extent = extent;
+            self.layout = layout;
+            self.images.clear();
+            self.images.resize(num_images, Vec::new());
+            self.add_damage(&[self.bounds()]);
+        }
+    }
+
+    /// Update the surface properties by querying `surface`.
+    ///
+    /// See [`DamageTracker::update`].
+    pub fn update_for_surface(&mut self, surface: &Surface) {
+        self.update_with_image_info(
+            &surface.image_info(),
+            surface.num_images(),
+            surface.does_preserve_image(),
+        );
+    }
+
+    /// Update the surface properties by querying `window`.
+    ///
+    /// See [`DamageTracker::update`].
+    pub fn update_for_window(&mut self, window: &SwWindow) {
+        self.update_with_image_info(
+            &window.image_info(),
+            window.num_images(),
+            window.does_preserve_image(),
+        );
+    }
+
+    /// Mark the specified regions as damaged in all swapchain images.
+    pub fn add_damage(&mut self, rects: &[Rect]) {
+        let bounds = self.bounds();
+
+        for image in self.images.iter_mut() {
+            for rect in rects.iter().filter_map(|r| r.intersection(&bounds)) {
+                add_rect(image, rect);
+            }
+        }
+    }
+
+    /// Get the regions the swapchain image at index `i` must repaint.
+    ///
+    /// Panics if `i` is out of range.
+    pub fn image_damage(&self, i: usize) -> &[Rect] {
+        &self.images[i]
+    }
+
+    /// Mark the swapchain image at index `i` as up-to-date.
+    ///
+    /// Panics if `i` is out of range.
+    pub fn mark_painted(&mut self, i: usize) {
+        self.images[i].clear();
+    }
+
+    fn bounds(&self) -> Rect {
+        Rect::new([0, 0], self.extent)
+    }
+}
+
+fn add_rect(region: &mut Vec<Rect>, rect: Rect) {
+    if region.iter().any(|r| contains(r, &rect)) {
+        return;
+    }
+
+    region.retain(|r| !contains(&rect, r));
+
+    if region.len() < MAX_RECTS {
+        region.push(rect);
+    } else {
+        let bbox = region.iter().fold(rect, |a, b| bounding_box(&a, b));
+        region.clear();
+        region.push(bbox);
+    }
+}
+
+fn contains(outer: &Rect, inner: &Rect) -> bool {
+    (0..2).all(|i| {
+        outer.origin[i] <= inner.origin[i]
+            && outer.origin[i] + outer.extent[i] >= inner.origin[i] + inner.extent[i]
+    })
+}
+
+fn bounding_box(a: &Rect, b: &Rect) -> Rect {
+    let min = [a.origin[0].min(b.origin[0]), a.origin[1].min(b.origin[1])];
+    let max = [
+        (a.origin[0] + a.extent[0]).max(b.origin[0] + b.extent[0]),
+        (a.origin[1] + a.extent[1]).max(b.origin[1] + b.extent[1]),
+    ];
+    Rect::new(min, [max[0] - min[0], max[1] - min[1]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multi_image() {
+        let mut tracker = DamageTracker::new();
+        tracker.update([100, 100], 2, true);
+
+        // Both images are initially undefined
+        assert_eq!(tracker.image_damage(0), &[Rect::new([0, 0], [100, 100])]);
+        assert_eq!(tracker.image_damage(1), &[Rect::new([0, 0], [100, 100])]);
+        tracker.mark_painted(0);
+
+        tracker.add_damage(&[Rect::new([10, 10], [5, 5])]);
+        assert_eq!(tracker.image_damage(0), &[Rect::new([10, 10], [5, 5])]);
+        assert_eq!(tracker.image_damage(1), &[Rect::new([0, 0], [100, 100])]);
+        tracker.mark_painted(1);
+
+        tracker.add_damage(&[Rect::new([50, 50], [5, 5])]);
+        assert_eq!(
+            tracker.image_damage(0),
+            &[Rect::new([10, 10], [5, 5]), Rect::new([50, 50], [5, 5])]
+        );
+        assert_eq!(tracker.image_damage(1), &[Rect::new([50, 50], [5, 5])]);
+        tracker.mark_painted(0);
+        assert_eq!(tracker.image_damage(0), &[]);
+
+        // Unchanged properties don't reset the tracker
+        tracker.update([100, 100], 2, true);
+        assert_eq!(tracker.image_damage(0), &[]);
+
+        tracker.update([100, 120], 2, true);
+        assert_eq!(tracker.image_damage(0), &[Rect::new([0, 0], [100, 120])]);
+    }
+
+    #[test]
+    fn clip_and_merge() {
+        let mut tracker = DamageTracker::new();
+        tracker.update([100, 100], 1, true);
+        tracker.mark_painted(0);
+
+        tracker.add_damage(&[Rect::new([90, 90], [20, 20])]);
+        assert_eq!(tracker.image_damage(0), &[Rect::new([90, 90], [10, 10])]);
+
+        tracker.add_damage(&[Rect::new([80, 80], [20, 20])]);
+        assert_eq!(tracker.image_damage(0), &[Rect::new([80, 80], [20, 20])]);
+
+        tracker.mark_painted(0);
+        let rects: Vec<_> = (0..MAX_RECTS as u32 + 1)
+            .map(|i| Rect::new([i * 10, 0], [1, 1]))
+            .collect();
+        tracker.add_damage(&rects);
+        assert_eq!(tracker.image_damage(0), &[Rect::new([0, 0], [81, 1])]);
+    }
+
+    #[test]
+    fn layout_change() {
+        let mut info = ImageInfo {
+            extent: [10, 10],
+            stride: 64,
+            format: Format::Argb8888,
+            scale: 1,
+        };
+        let mut tracker = DamageTracker::new();
+        tracker.update_with_image_info(&info, 1, true);
+        tracker.mark_painted(0);
+
+        tracker.update_with_image_info(&info, 1, true);
+        assert_eq!(tracker.image_damage(0), &[]);
+
+        info.format = Format::Xrgb8888;
+        tracker.update_with_image_info(&info, 1, true);
+        assert_eq!(tracker.image_damage(0), &[Rect::new([0, 0], [10, 10])]);
+        tracker.mark_painted(0);
+
+        info.stride = 128;
+        tracker.update_with_image_info(&info, 1, true);
+        assert_eq!(tracker.image_damage(0), &[Rect::new([0, 0], [10, 10])]);
+        tracker.mark_painted(0);
+
+        // `update` keeps the last known layout
+        tracker.update([10, 10], 1, true);
+        assert_eq!(tracker.image_damage(0), &[]);
+    }
+
+    #[test]
+    fn no_preserve() {
+        let mut tracker = DamageTracker::new();
+        tracker.update([10, 10], 1, false);
+        tracker.mark_painted(0);
+        tracker.update([10, 10], 1, false);
+        assert_eq!(tracker.image_damage(0), &[Rect::new([0, 0], [10, 10])]);
+    }
+}
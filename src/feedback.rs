@@ -0,0 +1,103 @@
+//! Presentation feedback
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// The maximum number of unretrieved feedback entries stored per surface.
+/// The oldest ones are discarded when this limit is exceeded.
+const MAX_QUEUE_LEN: usize = 64;
+
+/// Describes when a swapchain image presented by
+/// [`crate::Surface::present_image`] reached the screen. Retrieved by
+/// [`crate::Surface::poll_presentation_feedback`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PresentationFeedback {
+    /// The index of the presented swapchain image.
+    pub image_index: usize,
+    /// The time when the image was displayed. See
+    /// [`PresentationFlags::estimated`] for the accuracy.
+    pub presented_at: Instant,
+    /// The time until the next refresh of the output after `presented_at`.
+    /// `None` if unknown or the output doesn't have a constant refresh rate.
+    pub refresh_interval: Option<Duration>,
+    /// Describes how the presentation was done.
+    pub flags: PresentationFlags,
+}
+
+/// Describes how the presentation of a [`PresentationFeedback`] was done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct PresentationFlags {
+    /// The presentation was synchronized to the vertical retrace of the
+    /// output, so no tearing occurred.
+    pub vsync: bool,
+    /// `presented_at` was sampled by the display hardware.
+    pub hw_clock: bool,
+    /// The display hardware signaled the completion of the presentation.
+    pub hw_completion: bool,
+    /// The image was scanned out directly without being copied by the
+    /// windowing system.
+    pub zero_copy: bool,
+    /// The windowing system doesn't report presentation times, and
+    /// `presented_at` is the time when the image was handed over to it.
+    /// The image reaches the screen at some point later.
+    pub estimated: bool,
+}
+
+/// A queue of `PresentationFeedback` owned by a surface.
+#[derive(Debug, Default)]
+pub struct FeedbackQueue {
+    queue: RefCell<VecDeque<PresentationFeedback>>,
+}
+
+impl FeedbackQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&self, feedback: PresentationFeedback) {
+        let mut queue = self.queue.borrow_mut();
+        if queue.len() >= MAX_QUEUE_LEN {
+            queue.pop_front();
+        }
+        queue.push_back(feedback);
+    }
+
+    /// Push a best-effort `PresentationFeedback` for a backend that doesn't
+    /// report presentation times. This must be called when the image is handed
+    /// over to the windowing system.
+    pub fn push_estimated(&self, image_index: usize) {
+        self.push(PresentationFeedback {
+            image_index,
+            presented_at: Instant::now(),
+            refresh_interval: None,
+            flags: PresentationFlags {
+                estimated: true,
+                ..PresentationFlags::default()
+            },
+        });
+    }
+
+    pub fn pop(&self) -> Option<PresentationFeedback> {
+        self.queue.borrow_mut().pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queue_discards_oldest() {
+        let queue = FeedbackQueue::new();
+        for i in 0..MAX_QUEUE_LEN + 2 {
+            queue.push_estimated(i);
+        }
+
+        let indices: Vec<_> = std::iter::from_fn(|| queue.pop())
+            .map(|f| f.image_index)
+            .collect();
+        assert_eq!(indices, (2..MAX_QUEUE_LEN + 2).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,292 @@
+//! Headless backend - Swapchain images are plain memory buffers that are never
+//! displayed. Presented images are handed over to an application-supplied
+//! callback function instead.
+use owning_ref::OwningRefMut;
+use std::{
+    cell::{Cell, RefCell},
+    fmt,
+    ops::DerefMut,
+};
+
+use super::{
+    align::Align,
+    buffer::Buffer,
+    feedback::{FeedbackQueue, PresentationFeedback},
+    Config, Format, ImageInfo, Rect, SurfaceError,
+};
+
+pub(crate) type PresentCb = Box<dyn FnMut(usize, &ImageInfo, &[u8])>;
+
+pub struct SurfaceImpl {
+    images: Box<[RefCell<Buffer>]>,
+    /// The index of the image to be returned by `poll_next_image`.
+    next_image: Cell<usize>,
+    image_info: Cell<ImageInfo>,
+    scanline_align: Align,
+    present_cb: RefCell<Option<PresentCb>>,
+    feedback: FeedbackQueue,
+}
+
+impl fmt::Debug for SurfaceImpl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SurfaceImpl")
+            .field("images", &self.images)
+            .field("next_image", &self.next_image)
+            .field("image_info", &self.image_info)
+            .finish()
+    }
+}
+
+impl SurfaceImpl {
+    pub fn new(config: &Config, present_cb: Option<PresentCb>) -> Self {
+        let images: Vec<_> = (0..config.image_count.max(1))
+            .map(|_| RefCell::new(Buffer::from_size_align(1, config.align).unwrap()))
+            .collect();
+
+        Self {
+            images: images.into_boxed_slice(),
+            next_image: Cell::new(0),
+            image_info: Cell::new(ImageInfo::default()),
+            scanline_align: Align::new(config.scanline_align).unwrap(),
+            present_cb: RefCell::new(present_cb),
+            feedback: FeedbackQueue::new(),
+        }
+    }
+
+    pub fn update_surface(
+        &self,
+        extent: [u32; 2],
+        format: Format,
+        scale: u32,
+    ) -> Result<(), SurfaceError> {
+        if extent[0] == 0 || extent[1] == 0 {
+            return Err(SurfaceError::ZeroExtent);
+        }
+
+        // Fail-fast if some images are locked by the appliction
+        let mut images = self
+            .images
+            .iter()
+            .map(|image| image.try_borrow_mut())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| SurfaceError::ImageLocked)?;
+
+        use std::convert::TryInto;
+        let extent_usize: [usize; 2] = [
+            extent[0]
+                .try_into()
+                .map_err(|_| SurfaceError::ExtentTooLarge)?,
+            extent[1]
+                .try_into()
+                .map_err(|_| SurfaceError::ExtentTooLarge)?,
+        ];
+
+        let stride = extent_usize[0]
+            .checked_mul(format.bytes_per_pixel())
+            .and_then(|x| self.scanline_align.align_up(x))
+            .ok_or(SurfaceError::ExtentTooLarge)?;
+
+        let size = stride
+            .checked_mul(extent_usize[1])
+            .ok_or(SurfaceError::ExtentTooLarge)?;
+
+        // Allocate all images before replacing any of them so that a failure
+        // leaves the swapchain intact
+        let new_images = images
+            .iter()
+            .map(|image| image.try_new_like(size))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (image, new_image) in images.iter_mut().zip(new_images) {
+            **image = new_image;
+        }
+
+        self.image_info.set(ImageInfo {
+            extent,
+            stride,
+            format,
+            scale,
+        });
+
+        Ok(())
+    }
+
+    pub fn supported_formats(&self) -> impl Iterator<Item = Format> + '_ {
+        [
+            Format::Argb8888,
+            Format::Xrgb8888,
+            Format::Abgr8888,
+            Format::Xbgr8888,
+            Format::Rgb565,
+            Format::Argb2101010,
+            Format::Xrgb2101010,
+        ]
+        .iter()
+        .cloned()
+    }
+
+    pub fn image_info(&self) -> ImageInfo {
+        self.image_info.get()
+    }
+
+    pub fn num_images(&self) -> usize {
+        self.images.len()
+    }
+
+    pub fn does_preserve_image(&self) -> bool {
+        true
+    }
+
+    pub fn honors_scale(&self) -> bool {
+        false
+    }
+
+    pub fn poll_next_image(&self, filter: &dyn Fn(usize) -> bool) -> Option<usize> {
+        // Presentation completes synchronously, so every image is available.
+        // Images are handed out in a round-robin fashion to mimic a real
+        // swapchain.
+        let num_images = self.images.len();
+        (0..num_images)
+            .map(|k| (self.next_image.get() + k) % num_images)
+            .find(|&i| filter(i))
+    }
+
+    pub fn lock_image(&self, i: usize) -> Result<impl DerefMut<Target = [u8]> + '_, SurfaceError> {
+        let image = self.images.get(i).ok_or(SurfaceError::BadImageIndex(i))?;
+        let image = image
+            .try_borrow_mut()
+            .map_err(|_| SurfaceError::ImageLocked)?;
+        Ok(OwningRefMut::new(image).map_mut(|p| &mut **p))
+    }
+
+    pub fn set_destination_size(&self, _size: Option<[u32; 2]>) -> Result<(), SurfaceError> {
+        // There's no window to scale the image to
+        Ok(())
+    }
+
+    pub fn set_opaque_region(&self, _region: &[Rect]) {}
+
+    pub fn poll_presentation_feedback(&self) -> Option<PresentationFeedback> {
+        self.feedback.pop()
+    }
+
+    pub fn present_image(&self, i: usize, _damage: Option<&[Rect]>) -> Result<(), SurfaceError> {
+        let image = self.images.get(i).ok_or(SurfaceError::BadImageIndex(i))?;
+        let image = image.try_borrow().map_err(|_| SurfaceError::ImageLocked)?;
+
+        let image_info = self.image_info.get();
+        if image_info.extent[0] == 0 {
+            return Err(SurfaceError::NotInitialized);
+        }
+
+        if let Some(cb) = &mut *self.present_cb.borrow_mut() {
+            let len = image_info.stride * image_info.extent[1] as usize;
+            cb(i, &image_info, &image[..len]);
+        }
+
+        self.next_image.set((i + 1) % self.images.len());
+        self.feedback.push_estimated(i);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Config, Format, Surface, SurfaceError};
+    use std::{cell::RefCell, rc::Rc};
+
+    macro_rules! assert_err {
+        ($e:expr, $p:pat) => {
+            match $e {
+                Err($p) => {}
+                Err(e) => panic!("unexpected error: {:?}", e),
+                Ok(_) => panic!("unexpected success"),
+            }
+        };
+    }
+
+    #[test]
+    fn swapchain() {
+        let presented = Rc::new(RefCell::new(Vec::new()));
+        let config = Config {
+            image_count: 3,
+            scanline_align: 16,
+            ..Config::default()
+        };
+
+        let surface = {
+            let presented = Rc::clone(&presented);
+            Surface::new_headless_with_present_cb([5, 2], &config, move |i, info, data| {
+                assert_eq!(data.len(), info.stride * info.extent[1] as usize);
+                presented.borrow_mut().push((i, data[0]));
+            })
+        };
+
+        assert_eq!(surface.num_images(), 3);
+        assert_eq!(surface.image_info().extent, [5, 2]);
+        assert_eq!(surface.image_info().stride, 32);
+        assert_eq!(surface.image_info().scale, 1);
+
+        for frame in 0..4u8 {
+            let i = surface.poll_next_image().unwrap();
+            surface.lock_image(i)[0] = frame;
+            surface.present_image(i);
+        }
+
+        assert_eq!(*presented.borrow(), [(0, 0), (1, 1), (2, 2), (0, 3)]);
+    }
+
+    #[test]
+    fn presentation_feedback() {
+        let config = Config {
+            image_count: 2,
+            ..Config::default()
+        };
+        let surface = Surface::new_headless([4, 4], &config);
+        assert!(surface.poll_presentation_feedback().is_none());
+
+        for _ in 0..3 {
+            let i = surface.poll_next_image().unwrap();
+            surface.present_image(i);
+        }
+
+        let feedback: Vec<_> =
+            std::iter::from_fn(|| surface.poll_presentation_feedback()).collect();
+        let indices: Vec<_> = feedback.iter().map(|f| f.image_index).collect();
+        assert_eq!(indices, [0, 1, 0]);
+        assert!(feedback.iter().all(|f| f.flags.estimated));
+        assert!(feedback[0].presented_at <= feedback[2].presented_at);
+    }
+
+    #[test]
+    fn errors() {
+        let surface = Surface::new_headless([4, 4], &Config::default());
+
+        let i = surface.poll_next_image().unwrap();
+        let lock = surface.lock_image(i);
+
+        assert_err!(surface.try_lock_image(i), SurfaceError::ImageLocked);
+        assert_err!(surface.try_present_image(i), SurfaceError::ImageLocked);
+        assert_err!(
+            surface.try_update_surface([8, 8], Format::Argb8888),
+            SurfaceError::ImageLocked
+        );
+
+        drop(lock);
+
+        assert_err!(
+            surface.try_update_surface([0, 8], Format::Argb8888),
+            SurfaceError::ZeroExtent
+        );
+        assert_err!(
+            surface.try_update_surface_with_scale([8, 9], Format::Argb8888, 2),
+            SurfaceError::BadScale(2)
+        );
+        assert_err!(
+            surface.try_update_surface_with_scale([8, 8], Format::Argb8888, 0),
+            SurfaceError::BadScale(0)
+        );
+        assert_err!(surface.try_lock_image(42), SurfaceError::BadImageIndex(42));
+        surface.try_present_image(i).unwrap();
+    }
+}
@@ -11,17 +11,29 @@
 //! To this end, this crate is designed to panic only when preconditions are not
 //! met or under very pathologic circumstances that would cause winit to panic.
 //!
+//! Failures that can happen at runtime (e.g., running out of shared memory) are
+//! reported as [`SurfaceError`] by the `try_*` variants of the methods.
+//!
+//! # Cargo features
+//!
+//!  - `xcb`: Implement the X11 backend on XCB instead of Xlib. Images are sent
+//!    by plain `PutImage` requests, and errors are reported by
+//!    `present_image`. MIT-SHM, Present, and [`Config::shape_from_alpha`] are
+//!    not supported by this implementation.
+//!
 //! # Unimplemented features
 //!
-//!  - Partial update - The application can redraw a portion, but `swsurface`
-//!    always sends entire the windowfor now
+//!  - Windows: Partial update - `present_image_with_damage` always sends the
+//!    entire window
 //!  - Support for platforms other than: macOS, Windows, X11, Wayland
-//!  - X11: Support for color depths other than 24
-//!  - X11: Transparency
-//!  - Multi-threaded rendering (`Send`-able `Surface`)
+//!  - X11: Support for visuals other than TrueColor and DirectColor with 16
+//!    or 32 bits per pixel
+//!  - Multi-threaded presentation - `Surface` and [`Presenter`] must stay on
+//!    the event loop thread, and only [`Frame`]s can be sent to other threads
 //!  - Color management - we'll try to stick to sRGB for now
 //!
-use std::ops::{Deref, DerefMut};
+use either::Either;
+use std::{fmt, ops::DerefMut};
 use winit::{
     event_loop::EventLoop,
     window::{Window, WindowId},
@@ -32,7 +44,10 @@ use winit::{
 pub struct Config {
     /// Enable vertical sync.
     ///
-    /// This flag is merely a hint and may be ignored.
+    /// This flag is merely a hint and may be ignored. On Wayland, it causes
+    /// `poll_next_image` to wait for a frame callback from the compositor.
+    /// On X11 with the Present extension, it causes `poll_next_image` to wait
+    /// until the last presented image is displayed.
     pub vsync: bool,
 
     /// The preferred number of swapchain images. Must be `>= 1`.
@@ -62,10 +77,62 @@ pub struct Config {
     /// pre-multiplied alpha. You also have to specify an appropriate window
     /// creation option such as `WindowBuilder::with_transparent(true)` and use
     /// a [pixel format](Format) having an alpha channel for this option to
-    /// work.
+    /// work. On X11, this also requires a compositing manager to be running.
+    /// Use [`Surface::set_opaque_region`] to tell the presentation engine
+    /// which parts of the surface are opaque in this case.
     ///
     /// Defaults to `true`.
     pub opaque: bool,
+
+    /// Specifies whether the window shape is derived from the alpha channel
+    /// of presented images.
+    ///
+    /// If `true` is specified, pixels having alpha values less than 50% are
+    /// excluded from the window. This can be used to make a non-rectangular
+    /// window where per-pixel transparency is not available, e.g., on an X11
+    /// server without a compositing manager. Only the damaged regions are
+    /// scanned when an image is presented with damage. Otherwise, the entire
+    /// image is scanned.
+    ///
+    /// This option is only supported by X11 and is ignored by other backends.
+    ///
+    /// Defaults to `false`.
+    pub shape_from_alpha: bool,
+
+    /// Specifies whether `present_image` validates the pre-multiplied alpha
+    /// values of a non-opaque surface.
+    ///
+    /// A pixel having a color channel greater than its alpha channel is
+    /// invalid in pre-multiplied alpha and shows up as an artifact on some
+    /// presentation engines. Such pixels are typically caused by drawing
+    /// straight-alpha assets without converting them by
+    /// [`convert::premultiply_in_place`]. See [`AlphaCheck`] for the options.
+    ///
+    /// This option is only effective in a build with debug assertions enabled,
+    /// if `opaque` is `false`, and if the image format has an alpha channel.
+    /// Only the damaged regions of presented images are checked.
+    ///
+    /// Defaults to [`AlphaCheck::Off`].
+    pub alpha_check: AlphaCheck,
+}
+
+/// Specifies how `present_image` handles invalid pre-multiplied alpha values.
+/// See [`Config::alpha_check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AlphaCheck {
+    /// Don't check alpha values.
+    Off,
+    /// Log a warning for every presented image containing invalid pixels.
+    Warn,
+    /// Clamp the color channels of invalid pixels to the alpha value and log
+    /// a warning.
+    Clamp,
+}
+
+impl Default for AlphaCheck {
+    fn default() -> Self {
+        AlphaCheck::Off
+    }
 }
 
 impl Config {
@@ -83,6 +150,8 @@ impl Default for Config {
             align: 128,
             scanline_align: 128,
             opaque: true,
+            shape_from_alpha: false,
+            alpha_check: AlphaCheck::Off,
         }
     }
 }
@@ -97,19 +166,81 @@ pub enum Format {
     ///
     ///  - Wayland `argb8888` (`0`) (**mandatory**)
     ///  - Windows (**mandatory**)
+    ///  - X11 (**mandatory**)
     ///
     Argb8888,
 
     /// 32-bit RGB format.
     ///
     ///  - Wayland `xrgb8888` (`1`) (**mandatory**)
+    ///  - X11 (**mandatory**)
     ///
     Xrgb8888,
+
+    /// 32-bit ABGR format.
+    ///
+    ///  - Wayland `abgr8888` (`0x34324241`)
+    ///  - X11 with a 24/32-bit BGR visual
+    ///
+    Abgr8888,
+
+    /// 32-bit BGR format.
+    ///
+    ///  - Wayland `xbgr8888` (`0x34324258`)
+    ///  - X11 with a 24/32-bit BGR visual
+    ///
+    Xbgr8888,
+
+    /// 16-bit RGB format.
+    ///
+    ///  - Wayland `rgb565` (`0x36314752`)
+    ///  - X11 with a 16-bit visual
+    ///
+    Rgb565,
+
+    /// 32-bit ARGB format with 10-bit color channels and a 2-bit alpha
+    /// channel.
+    ///
+    ///  - Wayland `argb2101010` (`0x30335241`)
+    ///  - X11 with a 30-bit visual
+    ///
+    Argb2101010,
+
+    /// 32-bit RGB format with 10-bit color channels.
+    ///
+    ///  - Wayland `xrgb2101010` (`0x30335258`)
+    ///  - X11 with a 30-bit visual
+    ///
+    Xrgb2101010,
+}
+
+impl Format {
+    /// Get a flag indicating whether the format has an alpha channel.
+    pub fn has_alpha(self) -> bool {
+        match self {
+            Format::Argb8888 | Format::Abgr8888 | Format::Argb2101010 => true,
+            Format::Xrgb8888 | Format::Xbgr8888 | Format::Rgb565 | Format::Xrgb2101010 => false,
+        }
+    }
+
+    /// Get the size of a pixel, measured in bytes.
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            Format::Rgb565 => 2,
+            Format::Argb8888
+            | Format::Xrgb8888
+            | Format::Abgr8888
+            | Format::Xbgr8888
+            | Format::Argb2101010
+            | Format::Xrgb2101010 => 4,
+        }
+    }
 }
 
 /// Describes the format of a swapchain image.
 ///
-/// A swapchain image is a row-major top-down bitmap.
+/// A swapchain image is a row-major top-down bitmap. Each pixel is stored as
+/// a native-endian integer of [`Format::bytes_per_pixel`] bytes.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct ImageInfo {
     /// The image size (`[width, height]`), measured in bytes.
@@ -118,6 +249,11 @@ pub struct ImageInfo {
     pub stride: usize,
     /// The pixel format.
     pub format: Format,
+    /// The scale factor of the image, i.e., the number of image pixels per
+    /// logical pixel along each axis. On Wayland, this is passed to the
+    /// compositor via `wl_surface::set_buffer_scale`. Other backends map image
+    /// pixels to physical pixels regardless of this value.
+    pub scale: u32,
 }
 
 impl Default for ImageInfo {
@@ -126,10 +262,160 @@ impl Default for ImageInfo {
             extent: [0, 0],
             stride: 0,
             format: Format::Argb8888,
+            scale: 1,
+        }
+    }
+}
+
+/// A rectangular region in a swapchain image, measured in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Rect {
+    /// The top-left corner (`[x, y]`).
+    pub origin: [u32; 2],
+    /// The size (`[width, height]`).
+    pub extent: [u32; 2],
+}
+
+impl Rect {
+    /// Construct a `Rect`.
+    pub fn new(origin: [u32; 2], extent: [u32; 2]) -> Self {
+        Self { origin, extent }
+    }
+
+    /// Get a flag indicating whether the rectangle has no area.
+    pub fn is_empty(&self) -> bool {
+        self.extent[0] == 0 || self.extent[1] == 0
+    }
+
+    /// Get the intersection of two rectangles. Returns `None` if they don't
+    /// overlap.
+    pub fn intersection(&self, other: &Rect) -> Option<Rect> {
+        let min = [
+            self.origin[0].max(other.origin[0]),
+            self.origin[1].max(other.origin[1]),
+        ];
+        let max = [self.max(0).min(other.max(0)), self.max(1).min(other.max(1))];
+        if min[0] < max[0] && min[1] < max[1] {
+            Some(Rect::new(min, [max[0] - min[0], max[1] - min[1]]))
+        } else {
+            None
+        }
+    }
+
+    fn max(&self, axis: usize) -> u32 {
+        self.origin[axis].saturating_add(self.extent[axis])
+    }
+}
+
+/// Clip the damage region passed to `present_image_with_damage` to an image
+/// of the size `extent`. `None` represents the entire image.
+fn clip_damage<'a>(
+    damage: Option<&'a [Rect]>,
+    extent: [u32; 2],
+) -> impl Iterator<Item = Rect> + 'a {
+    let bounds = Rect::new([0, 0], extent);
+    match damage {
+        None => Either::Left(Some(bounds).filter(|r| !r.is_empty()).into_iter()),
+        Some(rects) => Either::Right(rects.iter().filter_map(move |r| r.intersection(&bounds))),
+    }
+}
+
+/// An error returned by the fallible (`try_*`) methods of [`Surface`] and
+/// [`SwWindow`].
+#[derive(Debug)]
+pub enum SurfaceError {
+    /// The specified pixel format is not in `supported_formats()`.
+    UnsupportedFormat(Format),
+    /// One of the extent's elements is zero.
+    ZeroExtent,
+    /// The scale factor is zero or the extent is not a multiple of it.
+    BadScale(u32),
+    /// The extent or the resulting image size is too large to be handled by
+    /// the backend.
+    ExtentTooLarge,
+    /// `update_surface` hasn't been called yet.
+    NotInitialized,
+    /// The swapchain image index is out of range.
+    BadImageIndex(usize),
+    /// The swapchain image is currently locked by `lock_image`.
+    ImageLocked,
+    /// The swapchain image is currently in use by the presentation engine.
+    ImageBusy,
+    /// The window does not belong to the windowing system (or the connection)
+    /// the [`Context`] was created for.
+    BackendMismatch,
+    /// A system library or a protocol interface required by the backend is
+    /// not available.
+    BackendUnavailable(&'static str),
+    /// The windowing system reported an error.
+    Platform(String),
+    /// Memory allocation failed.
+    OutOfMemory,
+    /// Shared memory could not be created or resized.
+    Shm(std::io::Error),
+    /// The rectangle doesn't fit in the swapchain image.
+    BadRect(Rect),
+    /// The source buffer passed to `write_from` doesn't cover the destination
+    /// rectangle.
+    BadSource,
+}
+
+impl fmt::Display for SurfaceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SurfaceError::UnsupportedFormat(format) => {
+                write!(f, "unsupported pixel format: {:?}", format)
+            }
+            SurfaceError::ZeroExtent => f.write_str("the image extent must not be zero"),
+            SurfaceError::BadScale(scale) => write!(
+                f,
+                "the scale factor {} is zero or doesn't evenly divide the image extent",
+                scale
+            ),
+            SurfaceError::ExtentTooLarge => f.write_str("the image extent is too large"),
+            SurfaceError::NotInitialized => f.write_str("surface is not initialized"),
+            SurfaceError::BadImageIndex(i) => write!(f, "invalid swapchain image index: {}", i),
+            SurfaceError::ImageLocked => f.write_str("the image is currently locked"),
+            SurfaceError::ImageBusy => {
+                f.write_str("the image is currently in use by the presentation engine")
+            }
+            SurfaceError::BackendMismatch => f.write_str("backend mismatch"),
+            SurfaceError::BackendUnavailable(what) => write!(f, "{} is not available", what),
+            SurfaceError::Platform(msg) => write!(f, "windowing system error: {}", msg),
+            SurfaceError::OutOfMemory => f.write_str("out of memory"),
+            SurfaceError::Shm(e) => write!(f, "shared memory error: {}", e),
+            SurfaceError::BadRect(rect) => write!(f, "rectangle out of bounds: {:?}", rect),
+            SurfaceError::BadSource => {
+                f.write_str("the source buffer doesn't cover the destination rectangle")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SurfaceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SurfaceError::Shm(e) => Some(e),
+            _ => None,
         }
     }
 }
 
+/// Unwrap a `Result` returned by a `try_*` method, panicking with the error's
+/// message.
+fn unwrap_or_panic<T>(result: Result<T, SurfaceError>) -> T {
+    result.unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Get the `AlphaCheck` applied to a surface created with `config`.
+fn effective_alpha_check(config: &Config) -> AlphaCheck {
+    if cfg!(debug_assertions) && !config.opaque {
+        config.alpha_check
+    } else {
+        AlphaCheck::Off
+    }
+}
+
 /// A software-rendered window.
 ///
 /// This is a safe wrapper around [`Surface`] and [`winit::window::Window`].
@@ -144,9 +430,23 @@ pub struct SwWindow {
 impl SwWindow {
     /// Construct a `SwWindow` by wrapping an existing `Window`.
     pub fn new(window: Window, context: &Context, config: &Config) -> Self {
-        Self {
-            surface: Some(unsafe { Surface::new(&window, context, config) }),
-            window: Some(window),
+        Self::try_new(window, context, config).unwrap_or_else(|e| panic!("{}", e.0))
+    }
+
+    /// Construct a `SwWindow` by wrapping an existing `Window`. Returns the
+    /// `Window` back on failure. The error is boxed because `Window` is
+    /// large.
+    pub fn try_new(
+        window: Window,
+        context: &Context,
+        config: &Config,
+    ) -> Result<Self, Box<(SurfaceError, Window)>> {
+        match unsafe { Surface::try_new(&window, context, config) } {
+            Ok(surface) => Ok(Self {
+                surface: Some(surface),
+                window: Some(window),
+            }),
+            Err(e) => Err(Box::new((e, window))),
         }
     }
 
@@ -160,7 +460,9 @@ impl SwWindow {
 
     /// Split the `Window` apart from the `Surface`.
     ///
-    /// **Unsafety:** The `Surface` must be dropped before the `Window`.
+    /// # Safety
+    ///
+    /// The `Surface` must be dropped before the `Window`.
     pub unsafe fn split(mut self) -> (Surface, Window) {
         (self.surface.take().unwrap(), self.window.take().unwrap())
     }
@@ -178,8 +480,40 @@ impl SwWindow {
             .update_surface(extent, format);
     }
 
-    /// Update the properties of the surface. The surface size is automatically
-    /// derived based on the window size.
+    /// Update the properties of the surface. Returns an error instead of
+    /// panicking.
+    pub fn try_update_surface(&self, extent: [u32; 2], format: Format) -> Result<(), SurfaceError> {
+        self.surface
+            .as_ref()
+            .unwrap()
+            .try_update_surface(extent, format)
+    }
+
+    /// Update the properties of the surface, specifying the scale factor of
+    /// the image.
+    pub fn update_surface_with_scale(&self, extent: [u32; 2], format: Format, scale: u32) {
+        self.surface
+            .as_ref()
+            .unwrap()
+            .update_surface_with_scale(extent, format, scale);
+    }
+
+    /// Update the properties of the surface, specifying the scale factor of
+    /// the image. Returns an error instead of panicking.
+    pub fn try_update_surface_with_scale(
+        &self,
+        extent: [u32; 2],
+        format: Format,
+        scale: u32,
+    ) -> Result<(), SurfaceError> {
+        self.surface
+            .as_ref()
+            .unwrap()
+            .try_update_surface_with_scale(extent, format, scale)
+    }
+
+    /// Update the properties of the surface. The surface size and the scale
+    /// factor are automatically derived based on the window.
     pub fn update_surface_to_fit(&self, format: Format) {
         self.surface
             .as_ref()
@@ -187,6 +521,36 @@ impl SwWindow {
             .update_surface_to_fit(self.window.as_ref().unwrap(), format);
     }
 
+    /// Update the properties of the surface. The surface size and the scale
+    /// factor are automatically derived based on the window. Returns an error
+    /// instead of panicking.
+    pub fn try_update_surface_to_fit(&self, format: Format) -> Result<(), SurfaceError> {
+        self.surface
+            .as_ref()
+            .unwrap()
+            .try_update_surface_to_fit(self.window.as_ref().unwrap(), format)
+    }
+
+    /// Set the size of the region where swapchain images are displayed,
+    /// measured in logical pixels.
+    pub fn set_destination_size(&self, size: Option<[u32; 2]>) {
+        self.surface.as_ref().unwrap().set_destination_size(size)
+    }
+
+    /// Set the size of the region where swapchain images are displayed,
+    /// measured in logical pixels. Returns an error instead of panicking.
+    pub fn try_set_destination_size(&self, size: Option<[u32; 2]>) -> Result<(), SurfaceError> {
+        self.surface
+            .as_ref()
+            .unwrap()
+            .try_set_destination_size(size)
+    }
+
+    /// Specify the regions of swapchain images that are known to be opaque.
+    pub fn set_opaque_region(&self, region: &[Rect]) {
+        self.surface.as_ref().unwrap().set_opaque_region(region)
+    }
+
     /// Enumerate supported pixel formats.
     pub fn supported_formats(&self) -> impl Iterator<Item = Format> + '_ {
         self.surface.as_ref().unwrap().supported_formats()
@@ -215,14 +579,95 @@ impl SwWindow {
     }
 
     /// Lock a swapchain image at index `i` to access its contents.
-    pub fn lock_image(&self, i: usize) -> impl Deref<Target = [u8]> + DerefMut + '_ {
+    pub fn lock_image(&self, i: usize) -> impl DerefMut<Target = [u8]> + '_ {
         self.surface.as_ref().unwrap().lock_image(i)
     }
 
+    /// Lock a swapchain image at index `i` to access its contents. Returns an
+    /// error instead of panicking.
+    pub fn try_lock_image(
+        &self,
+        i: usize,
+    ) -> Result<impl DerefMut<Target = [u8]> + '_, SurfaceError> {
+        self.surface.as_ref().unwrap().try_lock_image(i)
+    }
+
+    /// Lock a swapchain image at index `i` to access its contents as pixels.
+    pub fn lock_image_view(&self, i: usize) -> ImageLock<impl DerefMut<Target = [u8]> + '_> {
+        self.surface.as_ref().unwrap().lock_image_view(i)
+    }
+
+    /// Lock a swapchain image at index `i` to access its contents as pixels.
+    /// Returns an error instead of panicking.
+    pub fn try_lock_image_view(
+        &self,
+        i: usize,
+    ) -> Result<ImageLock<impl DerefMut<Target = [u8]> + '_>, SurfaceError> {
+        self.surface.as_ref().unwrap().try_lock_image_view(i)
+    }
+
+    /// Copy pixels into a swapchain image at index `i`, converting them to the
+    /// image's format.
+    pub fn write_from(&self, i: usize, src: &[u8], src_layout: &SourceLayout, dst_rect: Rect) {
+        self.surface
+            .as_ref()
+            .unwrap()
+            .write_from(i, src, src_layout, dst_rect)
+    }
+
+    /// Copy pixels into a swapchain image at index `i`, converting them to the
+    /// image's format. Returns an error instead of panicking.
+    pub fn try_write_from(
+        &self,
+        i: usize,
+        src: &[u8],
+        src_layout: &SourceLayout,
+        dst_rect: Rect,
+    ) -> Result<(), SurfaceError> {
+        self.surface
+            .as_ref()
+            .unwrap()
+            .try_write_from(i, src, src_layout, dst_rect)
+    }
+
     /// Enqueue the presentation of a swapchain image at index `i`.
     pub fn present_image(&self, i: usize) {
         self.surface.as_ref().unwrap().present_image(i)
     }
+
+    /// Enqueue the presentation of a swapchain image at index `i`. Returns an
+    /// error instead of panicking.
+    pub fn try_present_image(&self, i: usize) -> Result<(), SurfaceError> {
+        self.surface.as_ref().unwrap().try_present_image(i)
+    }
+
+    /// Enqueue the presentation of a swapchain image at index `i`, only
+    /// updating the specified regions.
+    pub fn present_image_with_damage(&self, i: usize, damage: &[Rect]) {
+        self.surface
+            .as_ref()
+            .unwrap()
+            .present_image_with_damage(i, damage)
+    }
+
+    /// Enqueue the presentation of a swapchain image at index `i`, only
+    /// updating the specified regions. Returns an error instead of panicking.
+    pub fn try_present_image_with_damage(
+        &self,
+        i: usize,
+        damage: &[Rect],
+    ) -> Result<(), SurfaceError> {
+        self.surface
+            .as_ref()
+            .unwrap()
+            .try_present_image_with_damage(i, damage)
+    }
+
+    /// Retrieve the oldest [`PresentationFeedback`] that hasn't been
+    /// retrieved yet.
+    pub fn poll_presentation_feedback(&self) -> Option<PresentationFeedback> {
+        self.surface.as_ref().unwrap().poll_presentation_feedback()
+    }
 }
 
 impl Drop for SwWindow {
@@ -271,11 +716,25 @@ mod unix;
 ))]
 use self::unix::{ContextImpl, SurfaceImpl};
 
+mod headless;
+
 // --------------------------------------------------------------------------
 // Helper types
 
 mod align;
 mod buffer;
+pub mod convert;
+mod damage;
+mod feedback;
+mod presenter;
+mod view;
+
+pub use self::damage::DamageTracker;
+pub use self::feedback::{PresentationFeedback, PresentationFlags};
+pub use self::presenter::{Frame, FrameMemory, Presenter};
+pub use self::view::{ImageLock, ImageViewMut, Pixel};
+
+use self::convert::SourceLayout;
 
 // --------------------------------------------------------------------------
 
@@ -298,6 +757,13 @@ impl<'a, T: 'static> ContextBuilder<'a, T> {
 
     /// Specify the function to be called when a swapchain image becomes
     /// available.
+    ///
+    /// On X11, the function is called by Xlib while it's reading events from
+    /// the connection, which may happen inside a method of [`Surface`] or an
+    /// Xlib call made by `winit`. It must not call Xlib functions or access
+    /// the surface. Use it to wake up the event loop, e.g., by
+    /// `EventLoopProxy::send_event` or `Window::request_redraw`. With the
+    /// `xcb` feature, it's called at the end of `present_image` instead.
     pub fn with_ready_cb(self, cb: impl Fn(WindowId) + 'static) -> Self {
         if ContextImpl::TAKES_READY_CB {
             Self {
@@ -311,9 +777,14 @@ impl<'a, T: 'static> ContextBuilder<'a, T> {
 
     /// Build a `Context`.
     pub fn build(self) -> Context {
-        Context {
-            inner: ContextImpl::new(self),
-        }
+        unwrap_or_panic(self.try_build())
+    }
+
+    /// Build a `Context`. Returns an error instead of panicking.
+    pub fn try_build(self) -> Result<Context, SurfaceError> {
+        Ok(Context {
+            inner: ContextImpl::new(self)?,
+        })
     }
 }
 
@@ -333,8 +804,8 @@ struct NullContextImpl;
 impl NullContextImpl {
     const TAKES_READY_CB: bool = false;
 
-    fn new<T: 'static>(_: ContextBuilder<'_, T>) -> Self {
-        Self {}
+    fn new<T: 'static>(_: ContextBuilder<'_, T>) -> Result<Self, SurfaceError> {
+        Ok(Self {})
     }
 }
 
@@ -342,19 +813,187 @@ impl NullContextImpl {
 
 /// A software-rendered surface that is implicitly associated with the
 /// underlying window (like `glutin::RawContext`).
+///
+/// A surface can also be created without a window by [`Surface::new_headless`].
 #[derive(Debug)]
 pub struct Surface {
-    inner: SurfaceImpl,
+    inner: SurfaceInner,
+    /// `Config::alpha_check`, or `AlphaCheck::Off` if the check is disabled
+    /// for the surface.
+    alpha_check: AlphaCheck,
+}
+
+/// Dispatches method calls to the backend of the current platform or the
+/// headless backend.
+#[derive(Debug)]
+enum SurfaceInner {
+    Window(Box<SurfaceImpl>),
+    Headless(headless::SurfaceImpl),
+}
+
+impl SurfaceInner {
+    fn update_surface(
+        &self,
+        extent: [u32; 2],
+        format: Format,
+        scale: u32,
+    ) -> Result<(), SurfaceError> {
+        match self {
+            SurfaceInner::Window(imp) => imp.update_surface(extent, format, scale),
+            SurfaceInner::Headless(imp) => imp.update_surface(extent, format, scale),
+        }
+    }
+
+    fn supported_formats(&self) -> impl Iterator<Item = Format> + '_ {
+        match self {
+            SurfaceInner::Window(imp) => Either::Left(imp.supported_formats()),
+            SurfaceInner::Headless(imp) => Either::Right(imp.supported_formats()),
+        }
+    }
+
+    fn image_info(&self) -> ImageInfo {
+        match self {
+            SurfaceInner::Window(imp) => imp.image_info(),
+            SurfaceInner::Headless(imp) => imp.image_info(),
+        }
+    }
+
+    fn num_images(&self) -> usize {
+        match self {
+            SurfaceInner::Window(imp) => imp.num_images(),
+            SurfaceInner::Headless(imp) => imp.num_images(),
+        }
+    }
+
+    fn does_preserve_image(&self) -> bool {
+        match self {
+            SurfaceInner::Window(imp) => imp.does_preserve_image(),
+            SurfaceInner::Headless(imp) => imp.does_preserve_image(),
+        }
+    }
+
+    /// Get a flag indicating whether the presentation engine scales images
+    /// down by `ImageInfo::scale`. Other backends ignore the scale.
+    fn honors_scale(&self) -> bool {
+        match self {
+            SurfaceInner::Window(imp) => imp.honors_scale(),
+            SurfaceInner::Headless(imp) => imp.honors_scale(),
+        }
+    }
+
+    fn poll_next_image(&self, filter: &dyn Fn(usize) -> bool) -> Option<usize> {
+        match self {
+            SurfaceInner::Window(imp) => imp.poll_next_image(filter),
+            SurfaceInner::Headless(imp) => imp.poll_next_image(filter),
+        }
+    }
+
+    fn lock_image(&self, i: usize) -> Result<impl DerefMut<Target = [u8]> + '_, SurfaceError> {
+        Ok(match self {
+            SurfaceInner::Window(imp) => Either::Left(imp.lock_image(i)?),
+            SurfaceInner::Headless(imp) => Either::Right(imp.lock_image(i)?),
+        })
+    }
+
+    fn set_destination_size(&self, size: Option<[u32; 2]>) -> Result<(), SurfaceError> {
+        match self {
+            SurfaceInner::Window(imp) => imp.set_destination_size(size),
+            SurfaceInner::Headless(imp) => imp.set_destination_size(size),
+        }
+    }
+
+    fn set_opaque_region(&self, region: &[Rect]) {
+        match self {
+            SurfaceInner::Window(imp) => imp.set_opaque_region(region),
+            SurfaceInner::Headless(imp) => imp.set_opaque_region(region),
+        }
+    }
+
+    fn poll_presentation_feedback(&self) -> Option<PresentationFeedback> {
+        match self {
+            SurfaceInner::Window(imp) => imp.poll_presentation_feedback(),
+            SurfaceInner::Headless(imp) => imp.poll_presentation_feedback(),
+        }
+    }
+
+    fn present_image(&self, i: usize, damage: Option<&[Rect]>) -> Result<(), SurfaceError> {
+        match self {
+            SurfaceInner::Window(imp) => imp.present_image(i, damage),
+            SurfaceInner::Headless(imp) => imp.present_image(i, damage),
+        }
+    }
 }
 
 impl Surface {
     /// Construct and attach a surface to the specified window.
     ///
-    /// **Unsafety:** The constructed `Surface` must be dropped before `window`.
+    /// # Safety
+    ///
+    /// The constructed `Surface` must be dropped before `window`.
     pub unsafe fn new(window: &Window, context: &Context, config: &Config) -> Self {
-        Self {
-            inner: SurfaceImpl::new(window, &context.inner, config),
-        }
+        unwrap_or_panic(Self::try_new(window, context, config))
+    }
+
+    /// Construct and attach a surface to the specified window. Returns an
+    /// error instead of panicking.
+    ///
+    /// # Safety
+    ///
+    /// The constructed `Surface` must be dropped before `window`.
+    pub unsafe fn try_new(
+        window: &Window,
+        context: &Context,
+        config: &Config,
+    ) -> Result<Self, SurfaceError> {
+        Ok(Self {
+            inner: SurfaceInner::Window(Box::new(SurfaceImpl::new(
+                window,
+                &context.inner,
+                config,
+            )?)),
+            alpha_check: effective_alpha_check(config),
+        })
+    }
+
+    /// Construct a surface that is not associated with any window.
+    ///
+    /// A headless surface implements the same swapchain semantics as other
+    /// surfaces, so it can be used to run rendering code on a machine without
+    /// a display server (e.g., for testing). Presented images are discarded.
+    /// Use [`Surface::new_headless_with_present_cb`] to retrieve them.
+    ///
+    /// The surface is initialized by calling `update_surface` with `extent`
+    /// and [`Format::Argb8888`]. All pixel formats are supported.
+    ///
+    /// Panics if one of `extent`'s elements is zero.
+    pub fn new_headless(extent: [u32; 2], config: &Config) -> Self {
+        Self::new_headless_inner(extent, config, None)
+    }
+
+    /// Construct a surface that is not associated with any window. `present_cb`
+    /// is called with the image index, the `ImageInfo`, and the contents of
+    /// the image every time a swapchain image is presented.
+    ///
+    /// See [`Surface::new_headless`] for more.
+    pub fn new_headless_with_present_cb(
+        extent: [u32; 2],
+        config: &Config,
+        present_cb: impl FnMut(usize, &ImageInfo, &[u8]) + 'static,
+    ) -> Self {
+        Self::new_headless_inner(extent, config, Some(Box::new(present_cb)))
+    }
+
+    fn new_headless_inner(
+        extent: [u32; 2],
+        config: &Config,
+        present_cb: Option<headless::PresentCb>,
+    ) -> Self {
+        let this = Self {
+            inner: SurfaceInner::Headless(headless::SurfaceImpl::new(config, present_cb)),
+            alpha_check: effective_alpha_check(config),
+        };
+        this.update_surface(extent, Format::Argb8888);
+        this
     }
 
     /// Update the properties of the surface.
@@ -363,27 +1002,139 @@ impl Surface {
     /// whether you want to change the image size or not. Also, you must call
     /// this method at least once before calling other methods.
     ///
-    /// The result of a mismatching image size is implementation-dependent.
+    /// The result of a mismatching image size is implementation-dependent
+    /// unless a destination size is set by [`Surface::set_destination_size`].
     /// In general, you should use `update_surface_to_fit`.
     ///
     /// Panics if:
     ///  - `format` is not in `supported_formats()`.
     ///  - One of `extent`'s elements is zero.
     ///  - One or more swapchain images are locked.
+    ///  - The backend failed to allocate swapchain images.
     pub fn update_surface(&self, extent: [u32; 2], format: Format) {
-        self.inner.update_surface(extent, format);
+        unwrap_or_panic(self.try_update_surface(extent, format));
+    }
+
+    /// Update the properties of the surface. Returns an error instead of
+    /// panicking if any of the conditions listed in `update_surface` occurs.
+    pub fn try_update_surface(&self, extent: [u32; 2], format: Format) -> Result<(), SurfaceError> {
+        self.try_update_surface_with_scale(extent, format, 1)
+    }
+
+    /// Update the properties of the surface, specifying the scale factor of
+    /// the image.
+    ///
+    /// `scale` is the number of image pixels per logical pixel and is reported
+    /// back through [`ImageInfo::scale`]. This method is equivalent to
+    /// `update_surface` if `scale` is `1`.
+    ///
+    /// Panics if any of the conditions listed in `update_surface` occurs, if
+    /// `scale` is zero, or if `extent` is not a multiple of `scale`.
+    pub fn update_surface_with_scale(&self, extent: [u32; 2], format: Format, scale: u32) {
+        unwrap_or_panic(self.try_update_surface_with_scale(extent, format, scale));
+    }
+
+    /// Update the properties of the surface, specifying the scale factor of
+    /// the image. Returns an error instead of panicking.
+    pub fn try_update_surface_with_scale(
+        &self,
+        extent: [u32; 2],
+        format: Format,
+        scale: u32,
+    ) -> Result<(), SurfaceError> {
+        if !self.supported_formats().any(|f| f == format) {
+            return Err(SurfaceError::UnsupportedFormat(format));
+        }
+        if extent[0] == 0 || extent[1] == 0 {
+            return Err(SurfaceError::ZeroExtent);
+        }
+        if extent.iter().any(|&x| x.checked_rem(scale) != Some(0)) {
+            return Err(SurfaceError::BadScale(scale));
+        }
+
+        self.inner.update_surface(extent, format, scale)
     }
 
-    /// Update the properties of the surface. The surface size is automatically
-    /// derived based on the window size.
+    /// Update the properties of the surface. The surface size and the scale
+    /// factor are automatically derived based on the window.
+    ///
+    /// On Wayland, the scale factor is `window.scale_factor()` rounded to the
+    /// nearest integer, and the image size is the window's physical size
+    /// rounded up to a multiple of it. Other platforms ignore the scale
+    /// factor, so the scale factor is `1` and the image size is the window's
+    /// physical size.
     ///
-    /// This internally calls `update_surface`.
+    /// This internally calls `update_surface_with_scale`.
     pub fn update_surface_to_fit(&self, window: &Window, format: Format) {
-        let (size_w, size_h) = window
-            .inner_size()
-            .into();
+        unwrap_or_panic(self.try_update_surface_to_fit(window, format));
+    }
+
+    /// Update the properties of the surface. The surface size and the scale
+    /// factor are automatically derived based on the window.
+    ///
+    /// This internally calls `try_update_surface_with_scale`.
+    pub fn try_update_surface_to_fit(
+        &self,
+        window: &Window,
+        format: Format,
+    ) -> Result<(), SurfaceError> {
+        let (size_w, size_h): (u32, u32) = window.inner_size().into();
+
+        if !self.inner.honors_scale() {
+            return self.try_update_surface_with_scale([size_w, size_h], format, 1);
+        }
+
+        let scale = window.scale_factor().round().max(1.0) as u32;
+        let round_up = |x: u32| {
+            let scale = u64::from(scale);
+            let x = (u64::from(x) + scale - 1) / scale * scale;
+            // Round down instead if the result doesn't fit in `u32`
+            if x > u64::from(u32::MAX) {
+                (x - scale) as u32
+            } else {
+                x as u32
+            }
+        };
+
+        self.try_update_surface_with_scale([round_up(size_w), round_up(size_h)], format, scale)
+    }
+
+    /// Set the size of the region where swapchain images are displayed,
+    /// measured in logical pixels. `None` restores the default behavior.
+    ///
+    /// When a destination size is set, the presentation engine scales
+    /// swapchain images to fit it, so the image extent doesn't have to match
+    /// the window size. For example, the application can render at a lower
+    /// resolution and have the compositor upscale the result, or use this
+    /// with a fractional scale factor. The window should be resized to the
+    /// destination size.
+    ///
+    /// This takes effect on the next call to `present_image`. It's currently
+    /// only supported on Wayland, and requires the compositor to support the
+    /// `wp_viewporter` protocol.
+    ///
+    /// Panics if it's not supported or one of `size`'s elements is zero.
+    pub fn set_destination_size(&self, size: Option<[u32; 2]>) {
+        unwrap_or_panic(self.try_set_destination_size(size))
+    }
+
+    /// Set the size of the region where swapchain images are displayed,
+    /// measured in logical pixels. Returns an error instead of panicking.
+    pub fn try_set_destination_size(&self, size: Option<[u32; 2]>) -> Result<(), SurfaceError> {
+        self.inner.set_destination_size(size)
+    }
 
-        self.update_surface([size_w, size_h], format);
+    /// Specify the regions of swapchain images that are known to be opaque,
+    /// measured in image pixels.
+    ///
+    /// This is merely a hint that lets the presentation engine skip blending
+    /// these regions. It's ignored if [`Config::opaque`] is `true` or the
+    /// image format doesn't have an alpha channel, in which case the entire
+    /// image is treated as opaque. This takes effect on the next call to
+    /// `present_image` and remains effective until it's called again. It's
+    /// currently only used on Wayland.
+    pub fn set_opaque_region(&self, region: &[Rect]) {
+        self.inner.set_opaque_region(region)
     }
 
     /// Enumerate supported pixel formats.
@@ -403,7 +1154,8 @@ impl Surface {
     /// This value does not reflect the actual number of buffers that stand
     /// between the application and the display hardware. It's only useful
     /// when `does_preserve_image() == true` and the application wants to
-    /// track dirty regions in each swapchain image.
+    /// track dirty regions in each swapchain image. [`DamageTracker`] can be
+    /// used for this purpose.
     pub fn num_images(&self) -> usize {
         self.inner.num_images()
     }
@@ -437,7 +1189,13 @@ impl Surface {
     /// `poll_next_image` repeatedly, it may return the same image index for
     /// all of the calls.
     pub fn poll_next_image(&self) -> Option<usize> {
-        self.inner.poll_next_image()
+        self.inner.poll_next_image(&|_| true)
+    }
+
+    /// Get the index of an available swapchain image for which `filter`
+    /// returns `true`. Otherwise behaves like `poll_next_image`.
+    pub(crate) fn poll_next_image_where(&self, filter: &dyn Fn(usize) -> bool) -> Option<usize> {
+        self.inner.poll_next_image(filter)
     }
 
     /// Lock a swapchain image at index `i` to access its contents.
@@ -449,10 +1207,88 @@ impl Surface {
     ///
     /// Given an `ImageInfo`, the length is calculated as:
     /// `extent[1] * stride * 4`.
-    pub fn lock_image(&self, i: usize) -> impl Deref<Target = [u8]> + DerefMut + '_ {
+    pub fn lock_image(&self, i: usize) -> impl DerefMut<Target = [u8]> + '_ {
+        unwrap_or_panic(self.try_lock_image(i))
+    }
+
+    /// Lock a swapchain image at index `i` to access its contents. Returns an
+    /// error instead of panicking.
+    pub fn try_lock_image(
+        &self,
+        i: usize,
+    ) -> Result<impl DerefMut<Target = [u8]> + '_, SurfaceError> {
         self.inner.lock_image(i)
     }
 
+    /// Lock a swapchain image at index `i` to access its contents as pixels.
+    ///
+    /// This is equivalent to `lock_image` except that the returned object
+    /// also provides a typed view through [`ImageLock::view_mut`], which
+    /// takes care of the stride and the pixel size given by `image_info()`:
+    ///
+    /// ```no_run
+    /// # fn f(surface: &swsurface::Surface, i: usize) {
+    /// let mut lock = surface.lock_image_view(i);
+    /// let mut image = lock.view_mut::<u32>();
+    /// for row in image.rows_mut() {
+    ///     for pixel in row.iter_mut() {
+    ///         *pixel = 0xff0080ff; // `Argb8888` or `Xrgb8888`
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub fn lock_image_view(&self, i: usize) -> ImageLock<impl DerefMut<Target = [u8]> + '_> {
+        unwrap_or_panic(self.try_lock_image_view(i))
+    }
+
+    /// Lock a swapchain image at index `i` to access its contents as pixels.
+    /// Returns an error instead of panicking.
+    pub fn try_lock_image_view(
+        &self,
+        i: usize,
+    ) -> Result<ImageLock<impl DerefMut<Target = [u8]> + '_>, SurfaceError> {
+        let lock = self.inner.lock_image(i)?;
+        Ok(ImageLock::new(lock, self.image_info()))
+    }
+
+    /// Copy the top-left `dst_rect.extent` pixels of `src` into the region
+    /// `dst_rect` of a swapchain image at index `i`, converting them to the
+    /// image's format. See [`convert::convert`] for details.
+    ///
+    /// `i` must be the index of a swapchain image acquired by `poll_next_image`.
+    ///
+    /// Panics if the image is currently locked or not ready to be accessed by
+    /// the application, `dst_rect` doesn't fit in the image, or `src` doesn't
+    /// cover `dst_rect`.
+    ///
+    /// ```no_run
+    /// use swsurface::{convert::{SourceFormat, SourceLayout}, Rect};
+    /// # fn f(surface: &swsurface::Surface, i: usize, rgba: &[u8]) {
+    /// let [width, height] = surface.image_info().extent;
+    /// let layout = SourceLayout::new(SourceFormat::Rgba8, [width, height]);
+    /// surface.write_from(i, rgba, &layout, Rect::new([0, 0], [width, height]));
+    /// # }
+    /// ```
+    pub fn write_from(&self, i: usize, src: &[u8], src_layout: &SourceLayout, dst_rect: Rect) {
+        unwrap_or_panic(self.try_write_from(i, src, src_layout, dst_rect))
+    }
+
+    /// Copy pixels into a swapchain image at index `i`. Returns an error
+    /// instead of panicking.
+    pub fn try_write_from(
+        &self,
+        i: usize,
+        src: &[u8],
+        src_layout: &SourceLayout,
+        dst_rect: Rect,
+    ) -> Result<(), SurfaceError> {
+        let image_info = self.image_info();
+        convert::validate(src, src_layout, &image_info, dst_rect)?;
+        let mut lock = self.inner.lock_image(i)?;
+        convert::convert(src, src_layout, &mut lock, &image_info, dst_rect);
+        Ok(())
+    }
+
     /// Enqueue the presentation of a swapchain image at index `i`.
     ///
     /// This method removes the swapchain image at index `i` from the set of
@@ -461,6 +1297,148 @@ impl Surface {
     /// `i` must be the index of a swapchain image acquired by `poll_next_image`.
     /// The image must not be locked by `lock_image`.
     pub fn present_image(&self, i: usize) {
-        self.inner.present_image(i)
+        unwrap_or_panic(self.try_present_image(i))
+    }
+
+    /// Enqueue the presentation of a swapchain image at index `i`. Returns an
+    /// error instead of panicking.
+    ///
+    /// On X11, the errors include the X protocol errors caused by the
+    /// presentation, e.g., when the window has already been destroyed.
+    pub fn try_present_image(&self, i: usize) -> Result<(), SurfaceError> {
+        self.check_alpha(i, None)?;
+        self.inner.present_image(i, None)
+    }
+
+    /// Enqueue the presentation of a swapchain image at index `i`, only
+    /// updating the regions specified by `damage`.
+    ///
+    /// `damage` is merely a hint. The backend may update a larger region (up
+    /// to the entire image), so the contents outside `damage` must be
+    /// identical to those of the previously presented image. Rectangles
+    /// outside the image are clipped. The first presentation after
+    /// `update_surface` always updates the entire image. Other requirements
+    /// are the same as `present_image`.
+    pub fn present_image_with_damage(&self, i: usize, damage: &[Rect]) {
+        unwrap_or_panic(self.try_present_image_with_damage(i, damage))
+    }
+
+    /// Enqueue the presentation of a swapchain image at index `i`, only
+    /// updating the regions specified by `damage`. Returns an error instead of
+    /// panicking.
+    pub fn try_present_image_with_damage(
+        &self,
+        i: usize,
+        damage: &[Rect],
+    ) -> Result<(), SurfaceError> {
+        self.check_alpha(i, Some(damage))?;
+        self.inner.present_image(i, Some(damage))
+    }
+
+    /// Implements `Config::alpha_check`.
+    fn check_alpha(&self, i: usize, damage: Option<&[Rect]>) -> Result<(), SurfaceError> {
+        if self.alpha_check == AlphaCheck::Off {
+            return Ok(());
+        }
+
+        let image_info = self.image_info();
+        if !image_info.format.has_alpha() {
+            return Ok(());
+        }
+
+        let bounds = Rect::new([0, 0], image_info.extent);
+        let rects: Vec<Rect> = match damage {
+            Some(damage) => damage
+                .iter()
+                .filter_map(|rect| rect.intersection(&bounds))
+                .collect(),
+            None => vec![bounds],
+        };
+
+        let clamp = self.alpha_check == AlphaCheck::Clamp;
+        let mut image = self.inner.lock_image(i)?;
+        for rect in rects {
+            let (count, first) =
+                convert::find_invalid_premultiplied(&mut image, &image_info, rect, clamp);
+            if let Some([x, y]) = first {
+                log::warn!(
+                    "Image {} has {} pixel(s) with invalid pre-multiplied alpha in {:?} \
+                     (first at [{}, {}]){}",
+                    i,
+                    count,
+                    rect,
+                    x,
+                    y,
+                    if clamp { "; clamped" } else { "" }
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Retrieve the oldest [`PresentationFeedback`] that hasn't been
+    /// retrieved yet.
+    ///
+    /// A feedback is queued when a presented image reaches the screen, in
+    /// the order of presentation. Images that never reach the screen (e.g.,
+    /// because they were superseded by a later one) don't produce feedback.
+    /// At most 64 feedbacks are kept, so call this method regularly (e.g.,
+    /// every frame) to avoid losing them.
+    ///
+    /// On Wayland, the timing is reported by the compositor if it supports
+    /// the `wp_presentation` protocol. Otherwise, and on other platforms, a
+    /// feedback is queued as soon as the image is handed over to the
+    /// windowing system, with [`PresentationFlags::estimated`] set.
+    pub fn poll_presentation_feedback(&self) -> Option<PresentationFeedback> {
+        self.inner.poll_presentation_feedback()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn alpha_check_clamp() {
+        let presented = Rc::new(RefCell::new(Vec::new()));
+        let config = Config {
+            opaque: false,
+            alpha_check: AlphaCheck::Clamp,
+            ..Config::default()
+        };
+
+        let surface = {
+            let presented = Rc::clone(&presented);
+            Surface::new_headless_with_present_cb([2, 1], &config, move |_, _, data| {
+                let pixel = |p: &[u8]| u32::from_ne_bytes([p[0], p[1], p[2], p[3]]);
+                presented
+                    .borrow_mut()
+                    .push([pixel(&data[0..4]), pixel(&data[4..8])]);
+            })
+        };
+
+        let pixels = [0x80ff4000u32, 0x80804000];
+        let write = |i| {
+            let mut lock = surface.lock_image_view(i);
+            lock.view_mut::<u32>().row_mut(0).copy_from_slice(&pixels);
+        };
+
+        let i = surface.poll_next_image().unwrap();
+        write(i);
+        surface.present_image(i);
+
+        // Pixels outside the damage are not checked
+        let i = surface.poll_next_image().unwrap();
+        write(i);
+        surface.present_image_with_damage(i, &[Rect::new([1, 0], [1, 1])]);
+
+        let expected = if cfg!(debug_assertions) {
+            [[0x80804000, 0x80804000], [0x80ff4000, 0x80804000]]
+        } else {
+            [pixels, pixels]
+        };
+        assert_eq!(*presented.borrow(), expected);
     }
 }
@@ -0,0 +1,472 @@
+//! Rendering on a worker thread
+use std::{
+    fmt,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex},
+};
+
+use super::{unwrap_or_panic, ImageLock, Rect, Surface, SurfaceError};
+
+/// A lock of a swapchain image held on behalf of a `Frame`. The lifetime is
+/// erased; `Presenter` guarantees that the lock doesn't outlive the surface.
+type ImageGuard = Box<dyn DerefMut<Target = [u8]>>;
+
+/// The indices of images whose `Frame`s were dropped without being presented.
+type AbandonedList = Arc<Mutex<Vec<usize>>>;
+
+/// Owns a [`Surface`] and hands out swapchain images as [`Frame`]s, which
+/// can be sent to and filled by other threads.
+///
+/// `Surface` can't be sent to another thread because most backends are
+/// bound to the thread running the event loop. `Presenter` splits the
+/// responsibilities: it stays on the event loop thread and acquires and
+/// presents images, while the pixels are written on a worker thread through
+/// a `Frame`, directly into the swapchain image.
+///
+/// ```no_run
+/// # fn f(surface: swsurface::Surface) {
+/// use std::{sync::mpsc, thread};
+///
+/// let mut presenter = swsurface::Presenter::new(surface);
+/// let (to_worker, jobs) = mpsc::channel::<swsurface::Frame>();
+/// let (to_presenter, done) = mpsc::channel();
+///
+/// thread::spawn(move || {
+///     for mut frame in jobs {
+///         frame.view_mut::<u32>().fill(0xff0080ff);
+///         to_presenter.send(frame).unwrap();
+///     }
+/// });
+///
+/// // On the event loop thread:
+/// if let Some(frame) = presenter.acquire_frame() {
+///     to_worker.send(frame).unwrap();
+/// }
+/// // ... later, e.g., after waking up the event loop:
+/// for frame in done.try_iter() {
+///     presenter.present_frame(frame);
+/// }
+/// # }
+/// ```
+///
+/// While a `Frame` is out, its image is locked. Methods of the surface that
+/// require the image to be unlocked, such as `update_surface` and
+/// `lock_image`, fail with [`SurfaceError::ImageLocked`] in the meantime.
+///
+/// # Leaks
+///
+/// If a `Presenter` is dropped while `Frame`s are still out, the surface is
+/// leaked so that the `Frame`s remain valid. The surface can't be dropped
+/// later by the last `Frame` instead because a `Frame` may be on another
+/// thread, where the surface must not be used. A leaked surface is never
+/// dropped, which means its swapchain images and windowing system resources
+/// (e.g., shared memory segments and pixmaps) stay allocated until the process
+/// exits. To avoid this, present or drop all `Frame`s first, or call
+/// [`Presenter::into_surface`] to check whether there are any left.
+pub struct Presenter {
+    /// The image locks held for `Frame`s, indexed by image index. They borrow
+    /// `surface` and must be dropped first.
+    locks: ManuallyDrop<Vec<Option<ImageGuard>>>,
+    /// Boxed so that the locks stay valid when `Presenter` is moved.
+    surface: ManuallyDrop<Box<Surface>>,
+    abandoned: AbandonedList,
+}
+
+impl Presenter {
+    /// Construct a `Presenter` by wrapping a `Surface`.
+    pub fn new(surface: Surface) -> Self {
+        Self {
+            locks: ManuallyDrop::new(Vec::new()),
+            surface: ManuallyDrop::new(Box::new(surface)),
+            abandoned: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Get a reference to the wrapped `Surface`.
+    ///
+    /// Use it to call `update_surface`, `poll_presentation_feedback`, and so
+    /// on. Images should be acquired and presented through the `Presenter`
+    /// instead.
+    pub fn surface(&self) -> &Surface {
+        &self.surface
+    }
+
+    /// Get the wrapped `Surface` back. Returns `self` back if there are
+    /// `Frame`s that haven't been presented or dropped yet.
+    pub fn into_surface(mut self) -> Result<Surface, Self> {
+        self.reclaim_abandoned();
+        if self.num_frames_out() > 0 {
+            return Err(self);
+        }
+
+        let mut this = ManuallyDrop::new(self);
+        unsafe {
+            ManuallyDrop::drop(&mut this.locks);
+            let surface = ManuallyDrop::into_inner(std::ptr::read(&this.surface));
+            std::ptr::drop_in_place(&mut this.abandoned);
+            Ok(*surface)
+        }
+    }
+
+    /// Get the number of `Frame`s that haven't been presented or dropped yet.
+    pub fn num_frames_out(&self) -> usize {
+        self.locks.iter().filter(|lock| lock.is_some()).count()
+    }
+
+    /// Acquire the next available swapchain image as a `Frame`.
+    ///
+    /// Available images that are already out as other `Frame`s are skipped.
+    /// Returns `None` if there is no available image left (a surface doesn't
+    /// always have more than one image available). Try again later in this
+    /// case, e.g., after presenting an outstanding frame.
+    ///
+    /// Panics if the image can't be locked.
+    pub fn acquire_frame(&mut self) -> Option<Frame> {
+        unwrap_or_panic(self.try_acquire_frame())
+    }
+
+    /// Acquire the next available swapchain image as a `Frame`. Returns an
+    /// error instead of panicking.
+    pub fn try_acquire_frame(&mut self) -> Result<Option<Frame>, SurfaceError> {
+        self.reclaim_abandoned();
+
+        let locks = &self.locks;
+        let is_out = |i: usize| locks.get(i).map_or(false, Option::is_some);
+        let i = match self.surface.poll_next_image_where(&|i| !is_out(i)) {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+
+        let image_info = self.surface.image_info();
+        let guard: Box<dyn DerefMut<Target = [u8]> + '_> =
+            Box::new(self.surface.try_lock_image(i)?);
+
+        // Safety: Erasing the lifetime is sound because the guard never
+        //         outlives the surface it borrows:
+        //          - `surface` is boxed, so it stays at the same address when
+        //            `Presenter` is moved.
+        //          - The guard is stored in `locks`, which is dropped before
+        //            `surface` in `into_surface` and `Drop`. If frames are
+        //            still out in `Drop`, neither of them is dropped.
+        //          - `Presenter` only exposes `&Surface`, so the surface can't
+        //            be replaced or dropped through `surface()`. Methods that
+        //            reallocate images, such as `update_surface`, fail with
+        //            `ImageLocked` while the guard exists.
+        let mut guard: ImageGuard = unsafe { std::mem::transmute(guard) };
+
+        let memory = FrameMemory {
+            ptr: guard.as_mut_ptr(),
+            len: guard.len(),
+        };
+
+        if self.locks.len() <= i {
+            self.locks.resize_with(i + 1, || None);
+        }
+        self.locks[i] = Some(guard);
+
+        Ok(Some(Frame {
+            lock: ImageLock::new(memory, image_info),
+            image_index: i,
+            abandoned: Some(Arc::clone(&self.abandoned)),
+        }))
+    }
+
+    /// Enqueue the presentation of a `Frame`'s image. See
+    /// [`Surface::present_image`].
+    ///
+    /// Panics if `frame` was acquired from another `Presenter` or the
+    /// presentation fails.
+    pub fn present_frame(&mut self, frame: Frame) {
+        unwrap_or_panic(self.try_present_frame(frame))
+    }
+
+    /// Enqueue the presentation of a `Frame`'s image. Returns an error instead
+    /// of panicking if the presentation fails.
+    pub fn try_present_frame(&mut self, frame: Frame) -> Result<(), SurfaceError> {
+        let i = self.release_frame(frame);
+        self.surface.try_present_image(i)
+    }
+
+    /// Enqueue the presentation of a `Frame`'s image, only updating the regions
+    /// specified by `damage`. See [`Surface::present_image_with_damage`].
+    pub fn present_frame_with_damage(&mut self, frame: Frame, damage: &[Rect]) {
+        unwrap_or_panic(self.try_present_frame_with_damage(frame, damage))
+    }
+
+    /// Enqueue the presentation of a `Frame`'s image, only updating the regions
+    /// specified by `damage`. Returns an error instead of panicking if the
+    /// presentation fails.
+    pub fn try_present_frame_with_damage(
+        &mut self,
+        frame: Frame,
+        damage: &[Rect],
+    ) -> Result<(), SurfaceError> {
+        let i = self.release_frame(frame);
+        self.surface.try_present_image_with_damage(i, damage)
+    }
+
+    /// Unlock the image of `frame` and return its index.
+    fn release_frame(&mut self, mut frame: Frame) -> usize {
+        // Check the ownership first so that a foreign frame, when dropped by
+        // unwinding, still returns the image to its own presenter
+        assert!(
+            Arc::ptr_eq(frame.abandoned.as_ref().unwrap(), &self.abandoned),
+            "the frame belongs to another presenter"
+        );
+        frame.abandoned = None;
+
+        let i = frame.image_index;
+        drop(frame);
+        self.locks[i] = None;
+        i
+    }
+
+    /// Unlock the images of the `Frame`s that were dropped without being
+    /// presented.
+    fn reclaim_abandoned(&mut self) {
+        let mut abandoned = self.abandoned.lock().unwrap();
+        for i in abandoned.drain(..) {
+            self.locks[i] = None;
+        }
+    }
+}
+
+impl Drop for Presenter {
+    fn drop(&mut self) {
+        self.reclaim_abandoned();
+        if self.num_frames_out() > 0 {
+            log::warn!(
+                "Leaking a surface because {} frame(s) are still out",
+                self.num_frames_out()
+            );
+            return;
+        }
+
+        unsafe {
+            ManuallyDrop::drop(&mut self.locks);
+            ManuallyDrop::drop(&mut self.surface);
+        }
+    }
+}
+
+impl fmt::Debug for Presenter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Presenter")
+            .field("surface", &**self.surface)
+            .field("num_frames_out", &self.num_frames_out())
+            .finish()
+    }
+}
+
+/// A swapchain image acquired by [`Presenter::acquire_frame`].
+///
+/// A `Frame` can be sent to another thread to fill the image. Dereferences to
+/// an [`ImageLock`] providing the image's contents. Pass it back to
+/// [`Presenter::present_frame`] to present the image. Dropping a `Frame`
+/// returns the image to the swapchain without presenting it.
+pub struct Frame {
+    lock: ImageLock<FrameMemory>,
+    image_index: usize,
+    /// `None` after the frame is returned to the presenter.
+    abandoned: Option<AbandonedList>,
+}
+
+impl Frame {
+    /// Get the index of the swapchain image.
+    pub fn image_index(&self) -> usize {
+        self.image_index
+    }
+}
+
+impl Deref for Frame {
+    type Target = ImageLock<FrameMemory>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.lock
+    }
+}
+
+impl DerefMut for Frame {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.lock
+    }
+}
+
+impl Drop for Frame {
+    fn drop(&mut self) {
+        if let Some(abandoned) = self.abandoned.take() {
+            abandoned.lock().unwrap().push(self.image_index);
+        }
+    }
+}
+
+impl fmt::Debug for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Frame")
+            .field("image_index", &self.image_index)
+            .field("image_info", self.lock.image_info())
+            .finish()
+    }
+}
+
+/// The contents of a swapchain image locked by a [`Frame`].
+pub struct FrameMemory {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// Safety: `FrameMemory` is the only pointer to the image's memory that's
+//         usable while the `Frame` is out, so sending it to another thread
+//         doesn't introduce a data race:
+//          - The `Presenter` holds the image's lock in `locks` from
+//            `try_acquire_frame` until the `Frame` is returned or dropped.
+//            Meanwhile, `lock_image` fails with `ImageLocked`, and backends
+//            don't read or reallocate a locked image (`present_image` and
+//            `update_surface` fail with `ImageLocked`).
+//          - The memory stays allocated while the lock is held (see the safety
+//            comment in `try_acquire_frame`), and `Frame` doesn't touch the
+//            memory after returning the image in `Frame::drop`.
+//          - The memory is plain bytes that aren't tied to a thread. Backends
+//            that share it with the windowing system (MIT-SHM, `wl_shm`) only
+//            let the server read it after `present_image`, which can't be
+//            called while the `Frame` is out.
+unsafe impl Send for FrameMemory {}
+
+impl Deref for FrameMemory {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl DerefMut for FrameMemory {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl fmt::Debug for FrameMemory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FrameMemory")
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Config, Format, Surface, SurfaceError};
+    use super::*;
+    use std::{cell::RefCell, rc::Rc, sync::mpsc, thread};
+
+    fn is_send<T: Send>() {}
+
+    #[test]
+    fn frame_is_send() {
+        is_send::<Frame>();
+    }
+
+    #[test]
+    fn render_on_worker_thread() {
+        let presented = Rc::new(RefCell::new(Vec::new()));
+        let config = Config {
+            image_count: 2,
+            ..Config::default()
+        };
+        let surface = {
+            let presented = Rc::clone(&presented);
+            Surface::new_headless_with_present_cb([4, 4], &config, move |i, _, data| {
+                presented.borrow_mut().push((i, data[0]));
+            })
+        };
+        let mut presenter = Presenter::new(surface);
+
+        let (to_worker, jobs) = mpsc::channel::<Frame>();
+        let (to_presenter, done) = mpsc::channel();
+        let worker = thread::spawn(move || {
+            for mut frame in jobs {
+                let value = frame.image_index() as u32 + 1;
+                frame.view_mut::<u32>().fill(value);
+                to_presenter.send(frame).unwrap();
+            }
+        });
+
+        for _ in 0..3 {
+            let frame = presenter.acquire_frame().unwrap();
+            to_worker.send(frame).unwrap();
+            presenter.present_frame(done.recv().unwrap());
+        }
+
+        drop(to_worker);
+        worker.join().unwrap();
+
+        assert_eq!(*presented.borrow(), [(0, 1), (1, 2), (0, 1)]);
+        assert!(presenter.into_surface().is_ok());
+    }
+
+    #[test]
+    fn frames_lock_images() {
+        let config = Config {
+            image_count: 1,
+            ..Config::default()
+        };
+        let mut presenter = Presenter::new(Surface::new_headless([4, 4], &config));
+
+        let frame = presenter.acquire_frame().unwrap();
+        assert_eq!(presenter.num_frames_out(), 1);
+
+        // The only image is already out
+        assert!(presenter.acquire_frame().is_none());
+
+        match presenter
+            .surface()
+            .try_update_surface([8, 8], Format::Argb8888)
+        {
+            Err(SurfaceError::ImageLocked) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // Dropping a frame returns the image to the swapchain
+        drop(frame);
+        let frame = presenter.acquire_frame().unwrap();
+
+        let presenter = presenter.into_surface().unwrap_err();
+        drop(frame);
+        presenter.into_surface().unwrap();
+    }
+
+    #[test]
+    fn multiple_frames_out() {
+        let config = Config {
+            image_count: 3,
+            ..Config::default()
+        };
+        let mut presenter = Presenter::new(Surface::new_headless([4, 4], &config));
+
+        let mut frames: Vec<_> = std::iter::from_fn(|| presenter.acquire_frame()).collect();
+        let indices: Vec<_> = frames.iter().map(Frame::image_index).collect();
+        assert_eq!(indices, [0, 1, 2]);
+        assert_eq!(presenter.num_frames_out(), 3);
+
+        // Returning one frame makes exactly that image available again
+        presenter.present_frame(frames.remove(1));
+        assert_eq!(presenter.acquire_frame().unwrap().image_index(), 1);
+    }
+
+    #[test]
+    fn foreign_frame() {
+        let mut presenter1 = Presenter::new(Surface::new_headless([4, 4], &Config::default()));
+        let mut presenter2 = Presenter::new(Surface::new_headless([4, 4], &Config::default()));
+
+        let frame = presenter1.acquire_frame().unwrap();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            presenter2.present_frame(frame);
+        }));
+        assert!(result.is_err());
+
+        // The frame was dropped by unwinding and returned to `presenter1`
+        assert!(presenter1.acquire_frame().is_some());
+    }
+}
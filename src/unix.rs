@@ -1,28 +1,44 @@
 //! Wayland/X11 backend
 use either::Either;
-use std::ops::{Deref, DerefMut};
+use std::{ffi::CStr, ops::DerefMut, os::raw::c_void};
 use winit::{platform::unix::*, window::Window};
 
-use super::{align::Align, Config, ContextBuilder, Format, ImageInfo};
+use super::{
+    align::Align, feedback::PresentationFeedback, Config, ContextBuilder, Format, ImageInfo, Rect,
+    SurfaceError,
+};
 
+mod shmpool;
 mod wayland;
+#[cfg(not(feature = "xcb"))]
 mod x11;
+#[cfg(feature = "xcb")]
+mod xcb;
+#[cfg(feature = "xcb")]
+mod xcbffi;
+#[cfg(not(feature = "xcb"))]
+mod xextffi;
+mod ximage;
+
+// The X11 backend is implemented on either Xlib or XCB
+#[cfg(feature = "xcb")]
+use self::xcb as x11;
 
 #[derive(Debug)]
 pub enum ContextImpl {
     Wayland(wayland::ContextImpl),
-    X11,
+    X11(x11::ContextImpl),
 }
 
 impl ContextImpl {
     pub const TAKES_READY_CB: bool = true;
 
-    pub fn new<T: 'static>(builder: ContextBuilder<'_, T>) -> Self {
+    pub fn new<T: 'static>(builder: ContextBuilder<'_, T>) -> Result<Self, SurfaceError> {
         unsafe {
-            match builder.event_loop.wayland_display() {
-                Some(wl_dpy) => ContextImpl::Wayland(wayland::ContextImpl::new(wl_dpy, builder)),
-                None => ContextImpl::X11,
-            }
+            Ok(match builder.event_loop.wayland_display() {
+                Some(wl_dpy) => ContextImpl::Wayland(wayland::ContextImpl::new(wl_dpy, builder)?),
+                None => ContextImpl::X11(x11::ContextImpl::new(builder)),
+            })
         }
     }
 }
@@ -30,48 +46,67 @@ impl ContextImpl {
 #[derive(Debug)]
 pub enum SurfaceImpl {
     Wayland(wayland::SurfaceImpl),
-    X11(x11::SurfaceImpl),
+    X11(Box<x11::SurfaceImpl>),
 }
 
 impl SurfaceImpl {
-    pub(crate) unsafe fn new(window: &Window, context: &ContextImpl, config: &Config) -> Self {
+    pub(crate) unsafe fn new(
+        window: &Window,
+        context: &ContextImpl,
+        config: &Config,
+    ) -> Result<Self, SurfaceError> {
         let scanline_align = Align::new(config.scanline_align).unwrap();
 
-        match (
-            window.wayland_display(),
-            window.wayland_surface(),
-            window.xlib_display(),
-            window.xlib_window(),
-        ) {
-            (Some(wl_dpy), Some(wl_srf), _, _) => match context {
-                ContextImpl::Wayland(context) => SurfaceImpl::Wayland(wayland::SurfaceImpl::new(
-                    wl_dpy,
-                    wl_srf,
-                    window.id(),
-                    context,
-                    config,
-                    scanline_align,
-                )),
-                ContextImpl::X11 => panic!("backend mismatch"),
+        #[cfg(not(feature = "xcb"))]
+        let x_conn = window.xlib_display();
+        #[cfg(feature = "xcb")]
+        let x_conn = window.xcb_connection();
+
+        Ok(
+            match (
+                window.wayland_display(),
+                window.wayland_surface(),
+                x_conn,
+                window.xlib_window(),
+            ) {
+                (Some(wl_dpy), Some(wl_srf), _, _) => match context {
+                    ContextImpl::Wayland(context) => {
+                        SurfaceImpl::Wayland(wayland::SurfaceImpl::new(
+                            wl_dpy,
+                            wl_srf,
+                            window.id(),
+                            context,
+                            config,
+                            scanline_align,
+                        )?)
+                    }
+                    ContextImpl::X11(_) => return Err(SurfaceError::BackendMismatch),
+                },
+                (None, None, Some(x_conn), Some(x_wnd)) => match context {
+                    ContextImpl::Wayland(_) => return Err(SurfaceError::BackendMismatch),
+                    ContextImpl::X11(context) => SurfaceImpl::X11(Box::new(x11::SurfaceImpl::new(
+                        x_conn,
+                        x_wnd,
+                        window.id(),
+                        context,
+                        config,
+                        scanline_align,
+                    )?)),
+                },
+                _ => unreachable!(),
             },
-            (None, None, Some(x_dpy), Some(x_wnd)) => match context {
-                ContextImpl::Wayland(_) => panic!("backend mismatch"),
-                ContextImpl::X11 => SurfaceImpl::X11(x11::SurfaceImpl::new(
-                    x_dpy,
-                    x_wnd,
-                    window.id(),
-                    config,
-                    scanline_align,
-                )),
-            },
-            _ => unreachable!(),
-        }
+        )
     }
 
-    pub fn update_surface(&self, extent: [u32; 2], format: Format) {
+    pub fn update_surface(
+        &self,
+        extent: [u32; 2],
+        format: Format,
+        scale: u32,
+    ) -> Result<(), SurfaceError> {
         match self {
-            SurfaceImpl::Wayland(imp) => imp.update_surface(extent, format),
-            SurfaceImpl::X11(imp) => imp.update_surface(extent, format),
+            SurfaceImpl::Wayland(imp) => imp.update_surface(extent, format, scale),
+            SurfaceImpl::X11(imp) => imp.update_surface(extent, format, scale),
         }
     }
 
@@ -103,24 +138,71 @@ impl SurfaceImpl {
         }
     }
 
-    pub fn poll_next_image(&self) -> Option<usize> {
+    pub fn honors_scale(&self) -> bool {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.honors_scale(),
+            SurfaceImpl::X11(imp) => imp.honors_scale(),
+        }
+    }
+
+    pub fn poll_next_image(&self, filter: &dyn Fn(usize) -> bool) -> Option<usize> {
         match self {
-            SurfaceImpl::Wayland(imp) => imp.poll_next_image(),
-            SurfaceImpl::X11(imp) => imp.poll_next_image(),
+            SurfaceImpl::Wayland(imp) => imp.poll_next_image(filter),
+            SurfaceImpl::X11(imp) => imp.poll_next_image(filter),
         }
     }
 
-    pub fn lock_image(&self, i: usize) -> impl Deref<Target = [u8]> + DerefMut + '_ {
+    pub fn lock_image(&self, i: usize) -> Result<impl DerefMut<Target = [u8]> + '_, SurfaceError> {
+        Ok(match self {
+            SurfaceImpl::Wayland(imp) => Either::Left(imp.lock_image(i)?),
+            SurfaceImpl::X11(imp) => Either::Right(imp.lock_image(i)?),
+        })
+    }
+
+    pub fn set_destination_size(&self, size: Option<[u32; 2]>) -> Result<(), SurfaceError> {
         match self {
-            SurfaceImpl::Wayland(imp) => Either::Left(imp.lock_image(i)),
-            SurfaceImpl::X11(imp) => Either::Right(imp.lock_image(i)),
+            SurfaceImpl::Wayland(imp) => imp.set_destination_size(size),
+            SurfaceImpl::X11(imp) => imp.set_destination_size(size),
         }
     }
 
-    pub fn present_image(&self, i: usize) {
+    pub fn set_opaque_region(&self, region: &[Rect]) {
         match self {
-            SurfaceImpl::Wayland(imp) => imp.present_image(i),
-            SurfaceImpl::X11(imp) => imp.present_image(i),
+            SurfaceImpl::Wayland(imp) => imp.set_opaque_region(region),
+            SurfaceImpl::X11(imp) => imp.set_opaque_region(region),
         }
     }
+
+    pub fn poll_presentation_feedback(&self) -> Option<PresentationFeedback> {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.poll_presentation_feedback(),
+            SurfaceImpl::X11(imp) => imp.poll_presentation_feedback(),
+        }
+    }
+
+    pub fn present_image(&self, i: usize, damage: Option<&[Rect]>) -> Result<(), SurfaceError> {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.present_image(i, damage),
+            SurfaceImpl::X11(imp) => imp.present_image(i, damage),
+        }
+    }
+}
+
+/// Load the first available library in `names` and return a function to look
+/// up its symbols. The library is never unloaded.
+unsafe fn open_library(names: &[&[u8]]) -> Option<impl Fn(&[u8]) -> Option<*mut c_void>> {
+    let lib = names
+        .iter()
+        .map(|name| libc::dlopen(name.as_ptr() as _, libc::RTLD_LAZY | libc::RTLD_LOCAL))
+        .find(|lib| !lib.is_null())?;
+
+    Some(move |name: &[u8]| {
+        let name = CStr::from_bytes_with_nul(name).unwrap();
+        let ptr = libc::dlsym(lib, name.as_ptr());
+        if ptr.is_null() {
+            None
+        } else {
+            Some(ptr)
+        }
+    })
 }
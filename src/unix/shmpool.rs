@@ -0,0 +1,164 @@
+//! `wl_shm_pool` backed by an anonymous shared memory file
+use log::trace;
+use std::{
+    fs::File,
+    io,
+    os::unix::io::{AsRawFd, FromRawFd},
+    ptr::null_mut,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use wayland_client::protocol::{wl_shm, wl_shm_pool};
+
+/// A `wl_shm_pool` and a memory mapping of the file backing it.
+///
+/// The pool can only grow because `wl_shm_pool::resize` can't shrink a pool.
+pub struct ShmPool {
+    file: File,
+    wl_pool: wl_shm_pool::WlShmPool,
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl ShmPool {
+    /// Construct a `ShmPool` of the specified size. `len` must be non-zero and
+    /// must fit in `i32`.
+    pub fn new(wl_shm: &wl_shm::WlShm, len: usize) -> io::Result<Self> {
+        debug_assert!(len > 0 && len <= i32::MAX as usize);
+
+        let file = create_shm_file()?;
+        file.set_len(len as u64)?;
+
+        let ptr = unsafe { map_file(&file, len)? };
+
+        trace!("Creating `wl_shm_pool` of size {}", len);
+
+        let wl_pool =
+            match wl_shm.create_pool(file.as_raw_fd(), len as i32, |p| p.implement_dummy()) {
+                Ok(wl_pool) => wl_pool,
+                Err(()) => {
+                    unsafe { libc::munmap(ptr as _, len) };
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "could not create `wl_shm_pool`",
+                    ));
+                }
+            };
+
+        Ok(Self {
+            file,
+            wl_pool,
+            ptr,
+            len,
+        })
+    }
+
+    /// Grow the pool to at least `len` bytes. `len` must fit in `i32`.
+    ///
+    /// This invalidates the pointer returned by `as_ptr`.
+    pub fn resize(&mut self, len: usize) -> io::Result<()> {
+        debug_assert!(len <= i32::MAX as usize);
+
+        if len <= self.len {
+            return Ok(());
+        }
+
+        trace!("Resizing `wl_shm_pool` from {} to {}", self.len, len);
+
+        self.file.set_len(len as u64)?;
+        let ptr = unsafe { map_file(&self.file, len)? };
+        self.wl_pool.resize(len as i32);
+
+        unsafe { libc::munmap(self.ptr as _, self.len) };
+        self.ptr = ptr;
+        self.len = len;
+
+        Ok(())
+    }
+
+    pub fn wl_pool(&self) -> &wl_shm_pool::WlShmPool {
+        &self.wl_pool
+    }
+
+    /// Get a pointer to the mapped contents of the pool.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+}
+
+impl Drop for ShmPool {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as _, self.len) };
+
+        // `wl_buffer`s created from the pool keep the pool's memory alive
+        // on the server side
+        self.wl_pool.destroy();
+    }
+}
+
+unsafe fn map_file(file: &File, len: usize) -> io::Result<*mut u8> {
+    let ptr = libc::mmap(
+        null_mut(),
+        len,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_SHARED,
+        file.as_raw_fd(),
+        0,
+    );
+    if ptr == libc::MAP_FAILED {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ptr as *mut u8)
+    }
+}
+
+/// Create an anonymous file that can be shared with the compositor.
+fn create_shm_file() -> io::Result<File> {
+    #[cfg(target_os = "linux")]
+    loop {
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_memfd_create,
+                b"swsurface\0".as_ptr(),
+                libc::MFD_CLOEXEC,
+            )
+        };
+        if fd >= 0 {
+            return Ok(unsafe { File::from_raw_fd(fd as _) });
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EINTR) => continue,
+            // The kernel is too old; fall back to POSIX shared memory
+            Some(libc::ENOSYS) => break,
+            _ => return Err(e),
+        }
+    }
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    loop {
+        let name = format!(
+            "/swsurface-{}-{}\0",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let fd = unsafe {
+            libc::shm_open(
+                name.as_ptr() as _,
+                libc::O_CREAT | libc::O_EXCL | libc::O_RDWR | libc::O_CLOEXEC,
+                0o600,
+            )
+        };
+        if fd >= 0 {
+            // Remove the name. The file stays alive until all references
+            // to it are closed.
+            unsafe { libc::shm_unlink(name.as_ptr() as _) };
+            return Ok(unsafe { File::from_raw_fd(fd) });
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EEXIST) | Some(libc::EINTR) => continue,
+            _ => return Err(e),
+        }
+    }
+}
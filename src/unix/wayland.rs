@@ -1,22 +1,31 @@
-use fragile::Fragile;
 use log::trace;
 use owning_ref::OwningRefMut;
-use smithay_client_toolkit::utils::MemPool;
 use std::{
     cell::{Cell, RefCell},
     fmt,
-    ops::{Deref, DerefMut},
+    ops::DerefMut,
     os::raw::c_void,
-    rc::Rc,
+    rc::{Rc, Weak},
+    time::{Duration, Instant},
 };
 use wayland_client::{
     self as wl,
-    protocol::{wl_buffer, wl_display, wl_shm, wl_surface},
+    protocol::{wl_buffer, wl_callback, wl_compositor, wl_display, wl_shm, wl_surface},
+};
+use wayland_protocols::{
+    presentation_time::client::{wp_presentation, wp_presentation_feedback},
+    viewporter::client::{wp_viewport, wp_viewporter},
 };
 use wayland_sys::{client::WAYLAND_CLIENT_HANDLE, ffi_dispatch};
 use winit::window::WindowId;
 
-use super::super::{align::Align, Config, ContextBuilder, Format, ImageInfo, ReadyCb};
+use super::super::{
+    align::Align,
+    clip_damage,
+    feedback::{FeedbackQueue, PresentationFeedback, PresentationFlags},
+    Config, ContextBuilder, Format, ImageInfo, ReadyCb, Rect, SurfaceError,
+};
+use super::shmpool::ShmPool;
 
 #[derive(Clone)]
 pub struct ContextImpl {
@@ -26,8 +35,18 @@ pub struct ContextImpl {
     // at least one instance of `winit::window::Window` created from it are
     // alive.
     wl_dpy: wl_display::WlDisplay,
+    wl_compositor: wl_compositor::WlCompositor,
     wl_shm: wl_shm::WlShm,
+    /// `None` if the compositor doesn't support `wp_viewporter`.
+    wp_viewporter: Option<wp_viewporter::WpViewporter>,
+    /// `None` if the compositor doesn't support `wp_presentation`.
+    wp_presentation: Option<wp_presentation::WpPresentation>,
+    /// The clock used by the timestamps of `wp_presentation_feedback`.
+    presentation_clock: libc::clockid_t,
     ready_cb: Rc<ReadyCb>,
+    /// Pixel formats advertised by `wl_shm`.
+    formats: Rc<[Format]>,
+    retired_buffers: Rc<RetiredBuffers>,
 }
 
 impl fmt::Debug for ContextImpl {
@@ -37,7 +56,10 @@ impl fmt::Debug for ContextImpl {
 }
 
 impl ContextImpl {
-    pub unsafe fn new<T: 'static>(wl_dpy_ptr: *mut c_void, builder: ContextBuilder<'_, T>) -> Self {
+    pub unsafe fn new<T: 'static>(
+        wl_dpy_ptr: *mut c_void,
+        builder: ContextBuilder<'_, T>,
+    ) -> Result<Self, SurfaceError> {
         let wl_dpy: wl_display::WlDisplay = wl::Proxy::from_c_ptr(wl_dpy_ptr as _).into();
 
         let manager = wl::GlobalManager::new(&wl_dpy);
@@ -48,35 +70,169 @@ impl ContextImpl {
             ffi_dispatch!(WAYLAND_CLIENT_HANDLE, wl_display_roundtrip, wl_dpy_ptr as _);
         }
 
-        let wl_shm: wl_shm::WlShm = manager
-            .instantiate_range(1, 1, |wl_shm| {
-                wl_shm.implement_closure(
-                    move |evt, _| {
-                        // `wl_shm` sends suppored formats via events
-                        if let wl_shm::Event::Format { format } = evt {
-                            let _ = format;
-                            // TODO: examine supported formats
-                        }
-                    },
-                    (),
-                )
-            })
-            .expect("server does not advertise `wl_shm`");
+        // `wl_compositor` is needed to create `wl_region`s
+        let wl_compositor: wl_compositor::WlCompositor = manager
+            .instantiate_range(1, 1, |wl_compositor| wl_compositor.implement_dummy())
+            .map_err(|_| SurfaceError::BackendUnavailable("`wl_compositor`"))?;
+
+        // `argb8888` and `xrgb8888` are always supported
+        let formats = Rc::new(RefCell::new(vec![Format::Argb8888, Format::Xrgb8888]));
+
+        let wl_shm: wl_shm::WlShm = {
+            let formats = Rc::clone(&formats);
+            manager
+                .instantiate_range(1, 1, |wl_shm| {
+                    wl_shm.implement_closure(
+                        move |evt, _| {
+                            // `wl_shm` sends suppored formats via events
+                            if let wl_shm::Event::Format { format } = evt {
+                                trace!("`wl_shm` supports {:?}", format);
+                                let mut formats = formats.borrow_mut();
+                                if let Some(format) = translate_format_from_wl(format) {
+                                    if !formats.contains(&format) {
+                                        formats.push(format);
+                                    }
+                                }
+                            }
+                        },
+                        (),
+                    )
+                })
+                .map_err(|_| SurfaceError::BackendUnavailable("`wl_shm`"))?
+        };
+
+        // `wp_viewporter` is optional
+        let wp_viewporter = manager
+            .instantiate_exact(1, |wp_viewporter| wp_viewporter.implement_dummy())
+            .ok();
+
+        // `wp_presentation` is optional, too
+        let presentation_clock = Rc::new(Cell::new(libc::CLOCK_MONOTONIC));
+        let wp_presentation = {
+            let presentation_clock = Rc::clone(&presentation_clock);
+            manager
+                .instantiate_exact(1, |wp_presentation| {
+                    wp_presentation.implement_closure(
+                        move |evt, _| {
+                            if let wp_presentation::Event::ClockId { clk_id } = evt {
+                                trace!("`wp_presentation` uses clock {}", clk_id);
+                                presentation_clock.set(clk_id as _);
+                            }
+                        },
+                        (),
+                    )
+                })
+                .ok()
+        };
+
+        // Receive the `format` and `clock_id` events
+        ffi_dispatch!(WAYLAND_CLIENT_HANDLE, wl_display_roundtrip, wl_dpy_ptr as _);
+
+        let formats: Vec<Format> = formats.borrow().clone();
 
-        Self {
+        Ok(Self {
             wl_dpy,
+            wl_compositor,
             wl_shm,
+            wp_viewporter,
+            wp_presentation,
+            presentation_clock: presentation_clock.get(),
 
             ready_cb: Rc::new(builder.ready_cb),
+            formats: formats.into(),
+            retired_buffers: Rc::new(RetiredBuffers::default()),
+        })
+    }
+}
+
+/// `wl_buffer`s that were discarded while still in use by the compositor,
+/// along with the pools they were created from.
+///
+/// Destroying a `wl_buffer` before the compositor releases it makes the
+/// surface contents undefined, so they are kept alive until the `release`
+/// event arrives. The remaining ones are destroyed when the last
+/// `ContextImpl` is dropped, at which point the display might not exist
+/// anymore.
+#[derive(Default)]
+struct RetiredBuffers {
+    buffers: RefCell<Vec<(wl_buffer::WlBuffer, Rc<ShmPool>)>>,
+}
+
+impl RetiredBuffers {
+    fn retire(&self, buffer: wl_buffer::WlBuffer, pool: Rc<ShmPool>) {
+        trace!("Retiring `wl_buffer` {:?}", buffer.as_ref().c_ptr());
+        self.buffers.borrow_mut().push((buffer, pool));
+    }
+
+    /// Destroy `buffer` if it was retired. Returns `true` if it was.
+    fn release(&self, buffer: &wl_buffer::WlBuffer) -> bool {
+        let mut buffers = self.buffers.borrow_mut();
+        if let Some(i) = buffers.iter().position(|(b, _)| b == buffer) {
+            let (buffer, _) = buffers.swap_remove(i);
+            trace!(
+                "Destroying retired `wl_buffer` {:?}",
+                buffer.as_ref().c_ptr()
+            );
+            buffer.destroy();
+            true
+        } else {
+            false
         }
     }
 }
 
+impl Drop for RetiredBuffers {
+    fn drop(&mut self) {
+        for (buffer, _) in self.buffers.get_mut().drain(..) {
+            trace!(
+                "Destroying retired `wl_buffer` {:?}",
+                buffer.as_ref().c_ptr()
+            );
+            buffer.destroy();
+        }
+    }
+}
+
+fn translate_format_from_wl(format: wl_shm::Format) -> Option<Format> {
+    match format {
+        wl_shm::Format::Argb8888 => Some(Format::Argb8888),
+        wl_shm::Format::Xrgb8888 => Some(Format::Xrgb8888),
+        wl_shm::Format::Abgr8888 => Some(Format::Abgr8888),
+        wl_shm::Format::Xbgr8888 => Some(Format::Xbgr8888),
+        wl_shm::Format::Rgb565 => Some(Format::Rgb565),
+        wl_shm::Format::Argb2101010 => Some(Format::Argb2101010),
+        wl_shm::Format::Xrgb2101010 => Some(Format::Xrgb2101010),
+        _ => None,
+    }
+}
+
+fn translate_format_to_wl(format: Format) -> wl_shm::Format {
+    match format {
+        Format::Argb8888 => wl_shm::Format::Argb8888,
+        Format::Xrgb8888 => wl_shm::Format::Xrgb8888,
+        Format::Abgr8888 => wl_shm::Format::Abgr8888,
+        Format::Xbgr8888 => wl_shm::Format::Xbgr8888,
+        Format::Rgb565 => wl_shm::Format::Rgb565,
+        Format::Argb2101010 => wl_shm::Format::Argb2101010,
+        Format::Xrgb2101010 => wl_shm::Format::Xrgb2101010,
+    }
+}
+
 #[derive(Debug)]
 pub struct SurfaceImpl {
     state: Rc<State>,
 }
 
+impl Drop for SurfaceImpl {
+    fn drop(&mut self) {
+        // A `wl_surface` can have at most one `wp_viewport`. Destroy it so
+        // that another `Surface` can be created for the same window.
+        if let Some(wp_viewport) = &self.state.wp_viewport {
+            wp_viewport.destroy();
+        }
+    }
+}
+
 /// This object is shared between `SharedImpl` and the event handler of
 /// `wl_buffer`.
 struct State {
@@ -84,15 +240,52 @@ struct State {
 
     wnd_id: WindowId,
     wl_srf: wl_surface::WlSurface,
+    /// `None` if the compositor doesn't support `wp_viewporter`.
+    wp_viewport: Option<wp_viewport::WpViewport>,
+
+    /// The shared memory pool from which all swapchain images are
+    /// sub-allocated. `RetiredBuffers` might also hold references to it.
+    ///
+    /// `None` at the initial state (i.e., before `update_surface` is called
+    /// for the first time).
+    pool: RefCell<Option<Rc<ShmPool>>>,
 
     images: Box<[Image]>,
 
-    /// If `true`, the `release` event handler will call `ready_cb` when
-    /// called for the next time.
+    /// If `true`, the `release` or `done` event handler will call `ready_cb`
+    /// when called for the next time and a swapchain image is available.
     enable_ready_cb: Cell<bool>,
 
+    /// `Config::vsync`. If `true`, we request a frame callback for each
+    /// presentation and don't return a swapchain image until it's done.
+    vsync: bool,
+
+    /// `true` if we have requested a frame callback but haven't received the
+    /// `done` event yet.
+    frame_pending: Cell<bool>,
+
     image_info: Cell<ImageInfo>,
     scanline_align: Align,
+
+    /// `true` if the next presentation has to damage the entire buffer
+    /// regardless of a damage region because the surface was resized.
+    needs_full_update: Cell<bool>,
+
+    /// `Config::opaque`. If `true`, the entire surface is marked as opaque.
+    opaque: bool,
+
+    /// The opaque region specified by `set_opaque_region`, measured in image
+    /// pixels.
+    opaque_region: RefCell<Vec<Rect>>,
+
+    /// `true` if the opaque region has to be sent to the compositor by the
+    /// next presentation.
+    opaque_region_dirty: Cell<bool>,
+
+    /// The size specified by `set_destination_size`.
+    destination_size: Cell<Option<[u32; 2]>>,
+
+    feedback: FeedbackQueue,
 }
 
 impl fmt::Debug for State {
@@ -102,47 +295,51 @@ impl fmt::Debug for State {
             .field("wnd_id", &self.wnd_id)
             .field("images", &self.images)
             .field("enable_ready_cb", &self.enable_ready_cb)
+            .field("vsync", &self.vsync)
+            .field("frame_pending", &self.frame_pending)
             .field("image_info", &self.image_info)
             .finish()
     }
 }
 
 struct Image {
-    /// `wl_shm_pool`, an associated shared memory region, and a `wl_buffer`
-    /// created from it.
+    /// The region of `State::pool` assigned to the image.
     ///
     /// `None` at the initial state (i.e., before `update_surface` is called
     /// for the first time).
-    ///
-    /// `wl_buffer` is created only when we are about to present it. Thus, the
-    /// valid states are:
-    ///
-    ///  1. `mem = Some(_, None)`, `presenting = false`
-    ///  1. `mem = Some(_, Some(_))`, `presenting = true`
-    ///  1. `mem = Some(_, Some(_))`, `presenting = false`
-    ///
-    mem: RefCell<Option<(MemPool, Option<wl_buffer::WlBuffer>)>>,
+    mem: RefCell<Option<ImageMem>>,
+
+    /// A `wl_buffer` created for the image and the parameters it was created
+    /// with. It's created when we are about to present the image for the
+    /// first time and is reused until the parameters change.
+    buffer: RefCell<Option<(BufferKey, wl_buffer::WlBuffer)>>,
 
     /// `true` if `mem` is currently in use by the server, i.e., we have sent
     /// it via `wl_surface::attach` but haven't received the `release` event.
-    /// FIXME: Could be merged into `MemPool::is_used()`
     presenting: Cell<bool>,
 }
 
-impl Drop for Image {
+/// A region of `State::pool`. `ptr` is valid until the pool is resized, which
+/// only happens in `update_surface` while all `ImageMem`s are borrowed.
+struct ImageMem {
+    ptr: *mut u8,
+    offset: usize,
+    len: usize,
+}
+
+/// The parameters of a `wl_buffer`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct BufferKey {
+    offset: i32,
+    extent: [u32; 2],
+    stride: i32,
+    format: Format,
+}
+
+impl Drop for State {
     fn drop(&mut self) {
-        let mem = self.mem.get_mut();
-        if let Some(mem) = mem {
-            if let Some(wl_buf) = mem.1.take() {
-                trace!("Destroying `wl_buffer` {:?}", wl_buf.as_ref().c_ptr());
-
-                // `wl_buf` could be still in use by the presenter, but there
-                // isn't much we can do. The Wayland connection might not even
-                // exist after this call to `drop`... (Remember that the
-                // connection is managed by `winit`)
-                wl_buf.destroy();
-            }
-        }
+        let pool = self.pool.get_mut().take();
+        self.discard_buffers(pool.as_ref());
     }
 }
 
@@ -160,122 +357,168 @@ impl SurfaceImpl {
         context: &ContextImpl,
         config: &Config,
         scanline_align: Align,
-    ) -> Self {
-        assert_eq!(wl_dpy, context.wl_dpy.as_ref().c_ptr() as _);
+    ) -> Result<Self, SurfaceError> {
+        if wl_dpy != context.wl_dpy.as_ref().c_ptr() as *mut c_void {
+            return Err(SurfaceError::BackendMismatch);
+        }
 
         let images: Vec<_> = (0..config.image_count)
             .map(|_| Image {
                 mem: RefCell::new(None),
+                buffer: RefCell::new(None),
                 presenting: Cell::new(false),
             })
             .collect();
 
         let wl_srf: wl_surface::WlSurface = wl::Proxy::from_c_ptr(wl_srf_ptr as _).into();
 
-        Self {
+        let wp_viewport = context.wp_viewporter.as_ref().and_then(|wp_viewporter| {
+            wp_viewporter
+                .get_viewport(&wl_srf, |wp_viewport| wp_viewport.implement_dummy())
+                .ok()
+        });
+
+        Ok(Self {
             state: Rc::new(State {
                 ctx: context.clone(),
                 wnd_id,
                 wl_srf,
+                wp_viewport,
+                pool: RefCell::new(None),
                 images: images.into_boxed_slice(),
                 enable_ready_cb: Cell::new(false),
+                vsync: config.vsync,
+                frame_pending: Cell::new(false),
                 image_info: Cell::new(ImageInfo::default()),
                 scanline_align,
+                needs_full_update: Cell::new(true),
+                opaque: config.opaque,
+                opaque_region: RefCell::new(Vec::new()),
+                opaque_region_dirty: Cell::new(true),
+                destination_size: Cell::new(None),
+                feedback: FeedbackQueue::new(),
             }),
-        }
+        })
     }
 
-    pub fn update_surface(&self, extent: [u32; 2], format: Format) {
-        assert_ne!(extent[0], 0);
-        assert_ne!(extent[1], 0);
+    pub fn update_surface(
+        &self,
+        extent: [u32; 2],
+        format: Format,
+        scale: u32,
+    ) -> Result<(), SurfaceError> {
+        if extent[0] == 0 || extent[1] == 0 {
+            return Err(SurfaceError::ZeroExtent);
+        }
 
         // Fail-fast if some images are locked by the appliction
-        let mut mems: Vec<_> = self
+        let mut mems = self
             .state
             .images
             .iter()
-            .map(|image| image.mem.try_borrow_mut().expect("some images are locked"))
-            .collect();
+            .map(|image| image.mem.try_borrow_mut())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| SurfaceError::ImageLocked)?;
 
         // Check the value range
-        assert!(extent[0] <= <i32>::max_value() as u32);
-        assert!(extent[1] <= <i32>::max_value() as u32);
+        if extent[0] > i32::MAX as u32 || extent[1] > i32::MAX as u32 {
+            return Err(SurfaceError::ExtentTooLarge);
+        }
 
         use std::convert::TryInto;
         let extent_usize: [usize; 2] = [
-            extent[0].try_into().expect("overflow"),
-            extent[1].try_into().expect("overflow"),
+            extent[0]
+                .try_into()
+                .map_err(|_| SurfaceError::ExtentTooLarge)?,
+            extent[1]
+                .try_into()
+                .map_err(|_| SurfaceError::ExtentTooLarge)?,
         ];
 
         let stride = extent_usize[0]
-            .checked_mul(4)
+            .checked_mul(format.bytes_per_pixel())
             .and_then(|x| self.state.scanline_align.align_up(x))
-            .expect("overflow");
+            .ok_or(SurfaceError::ExtentTooLarge)?;
 
         // `stride` must fit in `i32`
-        let _bytes_per_line: i32 = stride.try_into().unwrap();
+        let _bytes_per_line: i32 = stride
+            .try_into()
+            .map_err(|_| SurfaceError::ExtentTooLarge)?;
 
         // Calculate a new `ImageInfo`
         let image_info = ImageInfo {
             extent,
             stride,
             format,
+            scale,
         };
 
         trace!("{:?}: New image info = {:?}", self.state.wnd_id, image_info);
 
         let size = stride
             .checked_mul(image_info.extent[1] as usize)
-            .expect("overflow");
-
-        // Resize mempools
-        for (i, mem) in mems.iter_mut().enumerate() {
-            let (mem_pool, _) = mem.get_or_insert_with(|| {
-                // `MemPool` isn't created yet, so make one now
-                let state = Rc::clone(&self.state);
-
-                // `MemPool` doesn't call the event handler from another thread
-                // (AFAIK). It requires it to be `Send` only to allow you to
-                // create a `MemPool` for a `WlShm` originaing from another
-                // thread.  So assert that `state` will be used by the current
-                // thread.
-                let state = Fragile::new(state);
-
-                let on_release = move || {
-                    // Assert that we are using it from the correct thread
-                    let state = state.get();
-
-                    trace!("{:?}: Swapchain image {} was released", state.wnd_id, i);
-
-                    state.images[i].presenting.set(false);
-
-                    // Does the application want to receive a notification?
-                    // If so, reset this flag and call the ready callback.
-                    if state.enable_ready_cb.replace(false) {
-                        trace!("Calling `ready_cb`");
-                        (state.ctx.ready_cb)(state.wnd_id);
-                    }
-                };
-
-                trace!("Creating `MemPool`");
+            .ok_or(SurfaceError::ExtentTooLarge)?;
+
+        // All images are sub-allocated from a single pool, whose size must
+        // fit in `i32`
+        let pool_size = size
+            .checked_mul(mems.len())
+            .filter(|&x| x <= i32::MAX as usize)
+            .ok_or(SurfaceError::ExtentTooLarge)?;
+
+        let old_image_info = self.state.image_info.get();
+        let mut pool = self.state.pool.borrow_mut();
+
+        // The existing `wl_buffer`s and image regions remain valid unless the
+        // layout has changed
+        let layout_changed = pool.is_none()
+            || old_image_info.extent != image_info.extent
+            || old_image_info.stride != image_info.stride
+            || old_image_info.format != image_info.format;
+
+        if layout_changed {
+            // The pool can be reused only if the compositor isn't reading from
+            // it. Otherwise, allocate a new pool and leave the old one to the
+            // `wl_buffer`s being retired.
+            let any_presenting = self.state.images.iter().any(|i| i.presenting.get());
+            let reusable_pool = if any_presenting {
+                None
+            } else {
+                pool.as_mut().and_then(Rc::get_mut)
+            };
 
-                let mem_pool = MemPool::new(&self.state.ctx.wl_shm, on_release)
-                    .expect("could not create `wl_shm_pool`");
+            if let Some(pool) = reusable_pool {
+                pool.resize(pool_size).map_err(SurfaceError::Shm)?;
+                self.state.discard_buffers(None);
+            } else {
+                let new_pool = Rc::new(
+                    ShmPool::new(&self.state.ctx.wl_shm, pool_size).map_err(SurfaceError::Shm)?,
+                );
+                self.state.discard_buffers(pool.as_ref());
+                *pool = Some(new_pool);
+            }
 
-                (mem_pool, None)
-            });
+            let pool_ptr = pool.as_ref().unwrap().as_ptr();
 
-            trace!("Resizing `MemPool` to {}", size);
-            mem_pool
-                .resize(size)
-                .expect("could not resize the memory-mapped file");
+            // Assign regions to the images
+            for (i, mem) in mems.iter_mut().enumerate() {
+                **mem = Some(ImageMem {
+                    ptr: unsafe { pool_ptr.add(size * i) },
+                    offset: size * i,
+                    len: size,
+                });
+            }
         }
 
         self.state.image_info.set(image_info);
+        self.state.needs_full_update.set(true);
+        self.state.opaque_region_dirty.set(true);
+
+        Ok(())
     }
 
     pub fn supported_formats(&self) -> impl Iterator<Item = Format> + '_ {
-        [Format::Argb8888].iter().cloned()
+        self.state.ctx.formats.iter().cloned()
     }
 
     pub fn image_info(&self) -> ImageInfo {
@@ -290,12 +533,13 @@ impl SurfaceImpl {
         true
     }
 
-    pub fn poll_next_image(&self) -> Option<usize> {
-        let result = self
-            .state
-            .images
-            .iter()
-            .position(|image| image.presenting.get() == false);
+    /// The scale is applied by `wl_surface.set_buffer_scale`.
+    pub fn honors_scale(&self) -> bool {
+        true
+    }
+
+    pub fn poll_next_image(&self, filter: &dyn Fn(usize) -> bool) -> Option<usize> {
+        let result = self.state.next_image(filter);
 
         if let Some(i) = result {
             trace!(
@@ -323,53 +567,111 @@ impl SurfaceImpl {
         result
     }
 
-    pub fn lock_image(&self, i: usize) -> impl Deref<Target = [u8]> + DerefMut + '_ {
-        let image = &self.state.images[i];
+    pub fn lock_image(&self, i: usize) -> Result<impl DerefMut<Target = [u8]> + '_, SurfaceError> {
+        let image = self.image(i)?;
 
-        assert_eq!(
-            image.presenting.get(),
-            false,
-            "the image is currently in use by the compositor"
-        );
+        let mem = image
+            .mem
+            .try_borrow_mut()
+            .map_err(|_| SurfaceError::ImageLocked)?;
 
-        OwningRefMut::new(image.mem.borrow_mut()).map_mut(|x| {
-            // `update_surface` should have been called at least one.
-            // Otherwise, panic
-            x.as_mut()
-                .expect("surface is not initialized")
-                .0
-                // Get the underlying data of the memory-mapped file
-                .mmap()
-                .as_mut()
-        })
+        // `update_surface` should have been called at least one
+        if mem.is_none() {
+            return Err(SurfaceError::NotInitialized);
+        }
+
+        Ok(OwningRefMut::new(mem).map_mut(|x| {
+            let mem = x.as_mut().unwrap();
+            // Safety: `mem` is borrowed mutably, so the pool can't be
+            // remapped, and the regions of the images don't overlap
+            unsafe { std::slice::from_raw_parts_mut(mem.ptr, mem.len) }
+        }))
     }
 
-    pub fn present_image(&self, i: usize) {
-        let image = &self.state.images[i];
+    pub fn set_destination_size(&self, size: Option<[u32; 2]>) -> Result<(), SurfaceError> {
+        let wp_viewport = (self.state.wp_viewport.as_ref())
+            .ok_or(SurfaceError::BackendUnavailable("`wp_viewporter`"))?;
+
+        let (width, height) = match size {
+            // Unset the destination size
+            None => (-1, -1),
+            Some([0, _]) | Some([_, 0]) => return Err(SurfaceError::ZeroExtent),
+            Some(size) => {
+                use std::convert::TryInto;
+                let width: i32 = size[0]
+                    .try_into()
+                    .map_err(|_| SurfaceError::ExtentTooLarge)?;
+                let height: i32 = size[1]
+                    .try_into()
+                    .map_err(|_| SurfaceError::ExtentTooLarge)?;
+                (width, height)
+            }
+        };
 
-        assert_eq!(
-            image.presenting.get(),
-            false,
-            "the image is currently in use by the compositor"
+        trace!(
+            "{:?}: Setting the destination size to {:?}",
+            self.state.wnd_id,
+            size
         );
 
-        let mut mem = image.mem.try_borrow_mut().expect("the image is locked");
-        let (mem_pool, buffer_cell) = mem.as_mut().expect("surface is not initialized");
+        // This is a double-buffered state, which is applied by the next
+        // `present_image`
+        wp_viewport.set_destination(width, height);
+
+        // The opaque region is specified in the surface coordinates, which
+        // have just changed
+        self.state.destination_size.set(size);
+        self.state.opaque_region_dirty.set(true);
+
+        Ok(())
+    }
+
+    pub fn set_opaque_region(&self, region: &[Rect]) {
+        let mut opaque_region = self.state.opaque_region.borrow_mut();
+        opaque_region.clear();
+        opaque_region.extend(region.iter().filter(|r| !r.is_empty()).cloned());
+        self.state.opaque_region_dirty.set(true);
+    }
+
+    pub fn poll_presentation_feedback(&self) -> Option<PresentationFeedback> {
+        self.state.feedback.pop()
+    }
+
+    pub fn present_image(&self, i: usize, damage: Option<&[Rect]>) -> Result<(), SurfaceError> {
+        let image = self.image(i)?;
+
+        let mem = image
+            .mem
+            .try_borrow_mut()
+            .map_err(|_| SurfaceError::ImageLocked)?;
+        let mem = mem.as_ref().ok_or(SurfaceError::NotInitialized)?;
 
         let image_info = self.state.image_info.get();
-        let format = match image_info.format {
-            Format::Argb8888 => wl_shm::Format::Argb8888,
-            Format::Xrgb8888 => wl_shm::Format::Xrgb8888,
+        let key = BufferKey {
+            offset: mem.offset as i32,
+            extent: image_info.extent,
+            stride: image_info.stride as i32,
+            format: image_info.format,
         };
 
-        // Create `wl_buffer`.
-        let buffer = mem_pool.buffer(
-            0,
-            image_info.extent[0] as i32,
-            image_info.extent[1] as i32,
-            image_info.stride as i32,
-            format,
-        );
+        // Reuse the `wl_buffer` if it's still compatible with the image
+        let mut buffer_cell = image.buffer.borrow_mut();
+        let buffer = match &*buffer_cell {
+            Some((old_key, buffer)) if *old_key == key => buffer.clone(),
+            _ => {
+                let buffer = self.state.create_buffer(i, &key)?;
+
+                // The old `wl_buffer` isn't in use by the server because
+                // `presenting` is `false`
+                if let Some((_, old_buffer)) = buffer_cell.take() {
+                    trace!("Destroying `wl_buffer` {:?}", old_buffer.as_ref().c_ptr());
+                    old_buffer.destroy();
+                }
+
+                *buffer_cell = Some((key, buffer.clone()));
+                buffer
+            }
+        };
 
         trace!(
             "{:?}: Presenting swapchain image {} using `wl_buffer` {:?}",
@@ -378,24 +680,352 @@ impl SurfaceImpl {
             buffer.as_ref().c_ptr()
         );
 
-        // The previous statement also updates `MemPool`'s flag to indicate
-        // that `wl_buffer` is attached to a `wl_surface` and will raise the
-        // `release` event in the near future.
-        debug_assert!(mem_pool.is_used());
-
         // Attach the `wl_buffer` to the `wl_surface`.
         self.state.wl_srf.attach(Some(&buffer), 0, 0);
-        self.state
-            .wl_srf
-            .damage_buffer(0, 0, image_info.extent[0] as _, image_info.extent[1] as _);
-        self.state.wl_srf.commit();
 
-        if let Some(old_buffer) = buffer_cell.take() {
-            old_buffer.destroy();
+        if self.state.vsync {
+            self.state.request_frame();
+        }
+
+        if let Some(wp_presentation) = &self.state.ctx.wp_presentation {
+            self.state.request_presentation_feedback(wp_presentation, i);
         }
 
-        *buffer_cell = Some(buffer);
+        let damage = if self.state.needs_full_update.replace(false) {
+            // The image properties have changed. The buffer scale is applied
+            // on the next commit along with the new buffer.
+            self.state.wl_srf.set_buffer_scale(image_info.scale as i32);
+            None
+        } else {
+            damage
+        };
+
+        if self.state.opaque_region_dirty.get() {
+            self.state.update_opaque_region();
+        }
+
+        for rect in clip_damage(damage, image_info.extent) {
+            self.state.wl_srf.damage_buffer(
+                rect.origin[0] as _,
+                rect.origin[1] as _,
+                rect.extent[0] as _,
+                rect.extent[1] as _,
+            );
+        }
+
+        self.state.wl_srf.commit();
 
         image.presenting.set(true);
+
+        if self.state.ctx.wp_presentation.is_none() {
+            self.state.feedback.push_estimated(i);
+        }
+
+        Ok(())
+    }
+
+    /// Get the swapchain image at index `i`, checking that it's not in use
+    /// by the compositor.
+    fn image(&self, i: usize) -> Result<&Image, SurfaceError> {
+        let image = self
+            .state
+            .images
+            .get(i)
+            .ok_or(SurfaceError::BadImageIndex(i))?;
+
+        if image.presenting.get() {
+            return Err(SurfaceError::ImageBusy);
+        }
+
+        Ok(image)
+    }
+}
+
+impl State {
+    /// Create a `wl_buffer` for the swapchain image at index `i`.
+    fn create_buffer(
+        self: &Rc<Self>,
+        i: usize,
+        key: &BufferKey,
+    ) -> Result<wl_buffer::WlBuffer, SurfaceError> {
+        let pool = self.pool.borrow();
+        let pool = pool.as_ref().ok_or(SurfaceError::NotInitialized)?;
+
+        let state = Rc::downgrade(self);
+        let retired_buffers = Rc::downgrade(&self.ctx.retired_buffers);
+
+        let buffer = pool
+            .wl_pool()
+            .create_buffer(
+                key.offset,
+                key.extent[0] as i32,
+                key.extent[1] as i32,
+                key.stride,
+                translate_format_to_wl(key.format),
+                move |buffer| {
+                    buffer.implement_closure(
+                        move |evt, buffer| {
+                            if let wl_buffer::Event::Release = evt {
+                                // If the buffer was retired, it's no longer
+                                // associated with the swapchain image
+                                let retired = Weak::upgrade(&retired_buffers)
+                                    .map(|r| r.release(&buffer))
+                                    .unwrap_or(false);
+
+                                if let Some(state) = Weak::upgrade(&state) {
+                                    if !retired {
+                                        trace!(
+                                            "{:?}: Swapchain image {} was released",
+                                            state.wnd_id,
+                                            i
+                                        );
+                                        state.images[i].presenting.set(false);
+                                    }
+                                    state.notify_ready();
+                                }
+                            }
+                        },
+                        (),
+                    )
+                },
+            )
+            .map_err(|()| SurfaceError::Platform("could not create `wl_buffer`".to_owned()))?;
+
+        trace!(
+            "{:?}: Created `wl_buffer` {:?} for swapchain image {} ({:?})",
+            self.wnd_id,
+            buffer.as_ref().c_ptr(),
+            i,
+            key
+        );
+
+        Ok(buffer)
+    }
+
+    /// Remove the `wl_buffer`s from all swapchain images. The ones in use by
+    /// the compositor are handed over to `RetiredBuffers` with `pool`, and
+    /// their images are marked as available.
+    fn discard_buffers(&self, pool: Option<&Rc<ShmPool>>) {
+        for image in self.images.iter() {
+            let buffer = match image.buffer.borrow_mut().take() {
+                Some((_, buffer)) => buffer,
+                None => continue,
+            };
+
+            match pool {
+                Some(pool) if image.presenting.get() => {
+                    self.ctx.retired_buffers.retire(buffer, Rc::clone(pool));
+                    image.presenting.set(false);
+                }
+                _ => {
+                    debug_assert!(!image.presenting.get());
+                    trace!("Destroying `wl_buffer` {:?}", buffer.as_ref().c_ptr());
+                    buffer.destroy();
+                }
+            }
+        }
+    }
+
+    /// Get the index of an available swapchain image.
+    /// Get the index of the first available image for which `filter` returns
+    /// `true`.
+    fn next_image(&self, filter: &dyn Fn(usize) -> bool) -> Option<usize> {
+        if self.frame_pending.get() {
+            // Wait until the compositor tells us that it's a good time to
+            // draw a new frame
+            return None;
+        }
+
+        (0..self.images.len()).find(|&i| !self.images[i].presenting.get() && filter(i))
+    }
+
+    /// Call `ready_cb` if the application wants to receive a notification and
+    /// a swapchain image is now available.
+    fn notify_ready(&self) {
+        if self.enable_ready_cb.get() && self.next_image(&|_| true).is_some() {
+            self.enable_ready_cb.set(false);
+            trace!("Calling `ready_cb`");
+            (self.ctx.ready_cb)(self.wnd_id);
+        }
+    }
+
+    /// Send the opaque region to the compositor. This must be called before
+    /// `commit`.
+    fn update_opaque_region(&self) {
+        self.opaque_region_dirty.set(false);
+
+        let image_info = self.image_info.get();
+
+        let whole = [Rect::new([0, 0], image_info.extent)];
+        let opaque_region = self.opaque_region.borrow();
+        let rects = if self.opaque || !image_info.format.has_alpha() {
+            &whole[..]
+        } else {
+            &opaque_region[..]
+        };
+
+        trace!(
+            "{:?}: Setting the opaque region to {:?}",
+            self.wnd_id,
+            rects
+        );
+
+        if rects.is_empty() {
+            self.wl_srf.set_opaque_region(None);
+            return;
+        }
+
+        let wl_region = match self
+            .ctx
+            .wl_compositor
+            .create_region(|r| r.implement_dummy())
+        {
+            Ok(wl_region) => wl_region,
+            Err(()) => return,
+        };
+
+        // Convert the image coordinates to the surface coordinates, rounding
+        // inward so that translucent pixels are never marked as opaque
+        let surface_size = match self.destination_size.get() {
+            Some(size) => size,
+            None => [
+                image_info.extent[0] / image_info.scale,
+                image_info.extent[1] / image_info.scale,
+            ],
+        };
+        let map = |x: u32, axis: usize, round_up: bool| -> i32 {
+            let num = x as u64 * surface_size[axis] as u64;
+            let den = image_info.extent[axis] as u64;
+            (if round_up {
+                (num + den - 1) / den
+            } else {
+                num / den
+            }) as i32
+        };
+
+        for rect in clip_damage(Some(rects), image_info.extent) {
+            let min = [map(rect.origin[0], 0, true), map(rect.origin[1], 1, true)];
+            let max = [
+                map(rect.origin[0] + rect.extent[0], 0, false),
+                map(rect.origin[1] + rect.extent[1], 1, false),
+            ];
+            if min[0] < max[0] && min[1] < max[1] {
+                wl_region.add(min[0], min[1], max[0] - min[0], max[1] - min[1]);
+            }
+        }
+
+        self.wl_srf.set_opaque_region(Some(&wl_region));
+        wl_region.destroy();
+    }
+
+    /// Request presentation feedback for the swapchain image at index `i`.
+    /// This must be called before `commit`.
+    fn request_presentation_feedback(
+        self: &Rc<Self>,
+        wp_presentation: &wp_presentation::WpPresentation,
+        i: usize,
+    ) {
+        let state = Rc::downgrade(self);
+        let clock = self.ctx.presentation_clock;
+
+        // This fails if the surface is already dead, in which case there's
+        // nothing to report
+        let _ = wp_presentation.feedback(&self.wl_srf, move |feedback| {
+            feedback.implement_closure(
+                move |evt, _| {
+                    let state = match Weak::upgrade(&state) {
+                        Some(state) => state,
+                        None => return,
+                    };
+
+                    match evt {
+                        wp_presentation_feedback::Event::Presented {
+                            tv_sec_hi,
+                            tv_sec_lo,
+                            tv_nsec,
+                            refresh,
+                            flags,
+                            ..
+                        } => {
+                            use self::wp_presentation_feedback::Kind;
+
+                            let secs = (u64::from(tv_sec_hi) << 32) | u64::from(tv_sec_lo);
+                            let time = Duration::new(secs, tv_nsec);
+                            let has = |kind: Kind| flags & kind.to_raw() != 0;
+
+                            let feedback = PresentationFeedback {
+                                image_index: i,
+                                presented_at: instant_from_clock(clock, time),
+                                refresh_interval: if refresh == 0 {
+                                    None
+                                } else {
+                                    Some(Duration::from_nanos(refresh.into()))
+                                },
+                                flags: PresentationFlags {
+                                    vsync: has(Kind::Vsync),
+                                    hw_clock: has(Kind::HwClock),
+                                    hw_completion: has(Kind::HwCompletion),
+                                    zero_copy: has(Kind::ZeroCopy),
+                                    estimated: false,
+                                },
+                            };
+
+                            trace!("{:?}: Presented: {:?}", state.wnd_id, feedback);
+                            state.feedback.push(feedback);
+                        }
+                        wp_presentation_feedback::Event::Discarded => {
+                            trace!(
+                                "{:?}: Swapchain image {} was not displayed",
+                                state.wnd_id,
+                                i
+                            );
+                        }
+                        _ => {}
+                    }
+                },
+                (),
+            )
+        });
+    }
+
+    /// Request a frame callback. This must be called before `commit`.
+    fn request_frame(self: &Rc<Self>) {
+        let state = Rc::downgrade(self);
+
+        let result = self.wl_srf.frame(move |callback| {
+            callback.implement_closure(
+                move |evt, _| {
+                    if let wl_callback::Event::Done { .. } = evt {
+                        if let Some(state) = Weak::upgrade(&state) {
+                            trace!("{:?}: Received a frame callback", state.wnd_id);
+                            state.frame_pending.set(false);
+                            state.notify_ready();
+                        }
+                    }
+                },
+                (),
+            )
+        });
+
+        // `frame` fails if the surface is already dead. Don't wait for the
+        // callback in this case.
+        self.frame_pending.set(result.is_ok());
+    }
+}
+
+/// Convert a timestamp measured by the clock `clk_id` to `Instant`.
+fn instant_from_clock(clk_id: libc::clockid_t, time: Duration) -> Instant {
+    let now = Instant::now();
+
+    let mut ts: libc::timespec = unsafe { std::mem::zeroed() };
+    if unsafe { libc::clock_gettime(clk_id, &mut ts) } != 0 {
+        return now;
+    }
+    let clock_now = Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32);
+
+    if time <= clock_now {
+        now.checked_sub(clock_now - time).unwrap_or(now)
+    } else {
+        now + (time - clock_now)
     }
 }
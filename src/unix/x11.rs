@@ -1,30 +1,112 @@
-use log::debug;
+use log::{debug, trace};
 use owning_ref::OwningRefMut;
 use std::{
     cell::{Cell, RefCell},
+    ffi::CStr,
     fmt,
     ops::{Deref, DerefMut},
-    os::raw::{c_ulong, c_void},
+    os::raw::{c_char, c_int, c_ulong, c_void},
+    ptr::null_mut,
+    rc::{Rc, Weak},
+    slice::{from_raw_parts, from_raw_parts_mut},
+    sync::Mutex,
 };
 use winit::window::WindowId;
 use x11_dl::xlib;
 
-use super::super::{align::Align, buffer::Buffer, Config, Format, ImageInfo};
-
-// TODO: Non-opaque window
+use super::{
+    super::{
+        align::Align,
+        buffer::Buffer,
+        clip_damage,
+        feedback::{FeedbackQueue, PresentationFeedback},
+        Config, ContextBuilder, DamageTracker, Format, ImageInfo, ReadyCb, Rect, SurfaceError,
+    },
+    xextffi::{self as xext, XShmSegmentInfo, Xext, Xpresent},
+    ximage::{fill_alpha, put_image_pieces, PixelConverter, VisualLayout},
+};
 
 lazy_static::lazy_static! {
-    static ref XLIB: xlib::Xlib = xlib::Xlib::open().unwrap();
+    static ref XLIB: Option<xlib::Xlib> = xlib::Xlib::open().ok();
+    static ref XEXT: Option<Xext> = Xext::open();
+    static ref XPRESENT: Option<Xpresent> = Xpresent::open();
+
+    /// The Present event converters replaced by `present_cookie_hook`, keyed
+    /// by `Display` addresses. Entries of closed displays are not removed, but
+    /// are replaced when a new display gets the same address.
+    static ref PRESENT_COOKIE_PROCS: Mutex<Vec<(usize, Option<CookieProc>)>> =
+        Mutex::new(Vec::new());
+
+    /// The state of the active `ErrorTrap`.
+    static ref ERROR_TRAP: Mutex<Option<ErrorTrapState>> = Mutex::new(None);
+}
+
+thread_local! {
+    /// The surfaces receiving Present events through `present_cookie_hook`.
+    static PRESENT_STATES: RefCell<Vec<Weak<PresentState>>> = const { RefCell::new(Vec::new()) };
+}
+
+type CookieProc = unsafe extern "C" fn(
+    *mut xlib::Display,
+    *mut xlib::XGenericEventCookie,
+    *mut xlib::xEvent,
+) -> c_int;
+
+pub struct ContextImpl {
+    ready_cb: Rc<ReadyCb>,
+}
+
+impl fmt::Debug for ContextImpl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ContextImpl").finish()
+    }
+}
+
+impl ContextImpl {
+    pub fn new<T: 'static>(builder: ContextBuilder<'_, T>) -> Self {
+        Self {
+            ready_cb: Rc::new(builder.ready_cb),
+        }
+    }
 }
 
 pub struct SurfaceImpl {
     xlib: &'static xlib::Xlib,
     x_dpy: *mut xlib::Display,
     x_wnd: c_ulong,
-    x_scrn: *mut xlib::Screen,
+    /// The GC used for presentation. It has the window's depth, and its clip
+    /// rectangles are set to the updated regions on each presentation.
+    x_gc: xlib::GC,
+    /// The pixel layout of the window. `XImage`s must have this layout.
+    visual: VisualLayout,
+    /// Supported pixel formats. The formats not in
+    /// `visual.native_formats()` are converted by `converter`.
+    formats: Vec<Format>,
+    converter: PixelConverter,
+    /// The converted image and its stride. Only used if the image format
+    /// doesn't match the window's pixel layout.
+    convert_buf: RefCell<Buffer>,
+    convert_stride: Cell<usize>,
+    /// `Config::opaque`.
+    opaque: bool,
+    /// `libXext` if `Config::shape_from_alpha` is set and the SHAPE extension
+    /// is available.
+    shape_xext: Option<&'static Xext>,
     image_info: Cell<ImageInfo>,
-    image: RefCell<Buffer>,
+    images: Box<[RefCell<ImageMem>]>,
+    /// The Present swapchain. If `None`, there's only one image, which is
+    /// directly drawn onto the window.
+    present: Option<PresentChain>,
     scanline_align: Align,
+    align: usize,
+    /// The maximum request length in bytes.
+    max_request_len: usize,
+    /// `libXext` if the MIT-SHM extension is available and hasn't failed yet.
+    xext: Cell<Option<&'static Xext>>,
+    /// `true` if the next presentation has to send the entire image regardless
+    /// of a damage region because the window was resized.
+    needs_full_update: Cell<bool>,
+    feedback: FeedbackQueue,
 }
 
 impl fmt::Debug for SurfaceImpl {
@@ -37,71 +119,240 @@ impl SurfaceImpl {
     pub unsafe fn new(
         x_dpy: *mut c_void,
         x_wnd: c_ulong,
-        _wnd_id: WindowId,
+        wnd_id: WindowId,
+        context: &ContextImpl,
         config: &Config,
         scanline_align: Align,
-    ) -> Self {
-        let xlib = &*XLIB;
+    ) -> Result<Self, SurfaceError> {
+        let xlib = XLIB
+            .as_ref()
+            .ok_or(SurfaceError::BackendUnavailable("Xlib"))?;
         let x_dpy = x_dpy as *mut xlib::Display;
 
         // Get the window attributs
         let mut x_wnd_attrs: xlib::XWindowAttributes = std::mem::zeroed();
-        (xlib.XGetWindowAttributes)(x_dpy, x_wnd, &mut x_wnd_attrs);
-        let x_scrn = x_wnd_attrs.screen;
-        assert!(!x_scrn.is_null());
+        if (xlib.XGetWindowAttributes)(x_dpy, x_wnd, &mut x_wnd_attrs) == 0 {
+            return Err(SurfaceError::Platform(
+                "XGetWindowAttributes failed".to_owned(),
+            ));
+        }
+        // Note that a window created with
+        // `WindowBuilder::with_transparent(true)` uses a 32-bit TrueColor
+        // visual.
+        let visual = window_visual_layout(xlib, x_dpy, &x_wnd_attrs)?;
+        debug!("Window visual = {:?}", visual);
 
-        Self {
+        // `Argb8888` and `Xrgb8888` are always supported through conversion
+        let mut formats = visual.native_formats().to_vec();
+        for &format in &[Format::Argb8888, Format::Xrgb8888] {
+            if !formats.contains(&format) {
+                formats.push(format);
+            }
+        }
+
+        // Don't use the screen's default GC. It has the screen's default depth
+        // (usually 24), which might not match the window's, and it's shared
+        // with other code.
+        let x_gc = (xlib.XCreateGC)(x_dpy, x_wnd, 0, null_mut());
+        if x_gc.is_null() {
+            return Err(SurfaceError::Platform("XCreateGC failed".to_owned()));
+        }
+
+        let shape_xext = if config.shape_from_alpha {
+            let xext = XEXT
+                .as_ref()
+                .filter(|xext| (xext.XShapeQueryExtension)(x_dpy, &mut 0, &mut 0) != 0);
+            if xext.is_none() {
+                debug!("Ignoring `shape_from_alpha` because SHAPE is unavailable");
+            }
+            xext
+        } else {
+            None
+        };
+
+        let xext = XEXT
+            .as_ref()
+            .filter(|xext| is_shm_usable(xlib, xext, x_dpy));
+        debug!(
+            "MIT-SHM is {}",
+            if xext.is_some() { "usable" } else { "unusable" }
+        );
+
+        // `XExtendedMaxRequestSize` returns `0` if BIG-REQUESTS is unavailable
+        let max_request_len = match (xlib.XExtendedMaxRequestSize)(x_dpy) {
+            0 => (xlib.XMaxRequestSize)(x_dpy),
+            x => x,
+        } as usize
+            * 4;
+        debug!("Maximum request length = {} bytes", max_request_len);
+
+        let present = XPRESENT.as_ref().and_then(|xpresent| {
+            PresentChain::new(xlib, xpresent, x_dpy, x_wnd, wnd_id, context, config)
+        });
+        debug!(
+            "Present is {}",
+            if present.is_some() {
+                "usable"
+            } else {
+                "unusable"
+            }
+        );
+
+        let num_images = present.as_ref().map_or(1, |p| p.state.pixmaps.len());
+        let images: Vec<_> = (0..num_images)
+            .map(|_| {
+                RefCell::new(ImageMem::Heap(
+                    Buffer::from_size_align(1, config.align).unwrap(),
+                ))
+            })
+            .collect();
+
+        Ok(Self {
             xlib,
             x_dpy,
             x_wnd,
-            x_scrn,
+            x_gc,
+            visual,
+            formats,
+            converter: PixelConverter::new(visual),
+            convert_buf: RefCell::new(Buffer::from_size_align(1, config.align).unwrap()),
+            convert_stride: Cell::new(0),
+            opaque: config.opaque,
+            shape_xext,
             image_info: Cell::new(ImageInfo::default()),
-            image: RefCell::new(Buffer::from_size_align(1, config.align).unwrap()),
+            images: images.into_boxed_slice(),
+            present,
             scanline_align,
-        }
+            align: config.align,
+            max_request_len,
+            xext: Cell::new(xext),
+            needs_full_update: Cell::new(true),
+            feedback: FeedbackQueue::new(),
+        })
     }
 
-    pub fn update_surface(&self, extent: [u32; 2], format: Format) {
-        assert_ne!(extent[0], 0);
-        assert_ne!(extent[1], 0);
-        assert!(extent[0] <= <i32>::max_value() as u32);
-        assert!(extent[1] <= <i32>::max_value() as u32);
+    pub fn update_surface(
+        &self,
+        extent: [u32; 2],
+        format: Format,
+        scale: u32,
+    ) -> Result<(), SurfaceError> {
+        if extent[0] == 0 || extent[1] == 0 {
+            return Err(SurfaceError::ZeroExtent);
+        }
+        if extent[0] > i32::MAX as u32 || extent[1] > i32::MAX as u32 {
+            return Err(SurfaceError::ExtentTooLarge);
+        }
 
         use std::convert::TryInto;
         let extent_usize: [usize; 2] = [
-            extent[0].try_into().expect("overflow"),
-            extent[1].try_into().expect("overflow"),
+            extent[0]
+                .try_into()
+                .map_err(|_| SurfaceError::ExtentTooLarge)?,
+            extent[1]
+                .try_into()
+                .map_err(|_| SurfaceError::ExtentTooLarge)?,
         ];
 
+        // The X server expects each row to be padded to 32 bits
+        let row_align = Align::new(4).unwrap();
+
         let stride = extent_usize[0]
-            .checked_mul(4)
+            .checked_mul(format.bytes_per_pixel())
             .and_then(|x| self.scanline_align.align_up(x))
-            .expect("overflow");
+            .and_then(|x| row_align.align_up(x))
+            .ok_or(SurfaceError::ExtentTooLarge)?;
 
         // `stride` must fit in `XImage::bytes_per_line`
-        let _bytes_per_line: i32 = stride.try_into().unwrap();
+        let _bytes_per_line: i32 = stride
+            .try_into()
+            .map_err(|_| SurfaceError::ExtentTooLarge)?;
+
+        let size = stride
+            .checked_mul(extent_usize[1])
+            .ok_or(SurfaceError::ExtentTooLarge)?;
+
+        // Fail-fast if some images are locked by the appliction
+        let mut images = self
+            .images
+            .iter()
+            .map(|image| image.try_borrow_mut())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| SurfaceError::ImageLocked)?;
+
+        let direct = self.visual.native_formats().contains(&format);
+        // Whether the image is sent through `convert_buf`
+        let staged = !direct || self.visual.fills_alpha(format, self.opaque);
+
+        if !staged {
+            self.convert_buf.borrow_mut().resize(1)?;
+        } else {
+            // Allocate a buffer for the converted image or the copy with
+            // filled alpha values
+            let convert_stride = extent_usize[0]
+                .checked_mul(self.visual.bits_per_pixel as usize / 8)
+                .and_then(|x| row_align.align_up(x))
+                .ok_or(SurfaceError::ExtentTooLarge)?;
+            let convert_size = convert_stride
+                .checked_mul(extent_usize[1])
+                .ok_or(SurfaceError::ExtentTooLarge)?;
+            self.convert_buf.borrow_mut().resize(convert_size)?;
+            self.convert_stride.set(convert_stride);
+
+            // MIT-SHM doesn't help because the copy is sent
+            for image in images.iter_mut() {
+                if let ImageMem::Shm(_) = &**image {
+                    **image = ImageMem::Heap(Buffer::from_size_align(1, self.align).unwrap());
+                }
+            }
+        }
 
-        let size = stride.checked_mul(extent_usize[1]).expect("overflow");
+        for image in images.iter_mut() {
+            if let (Some(xext), false) = (self.xext.get(), staged) {
+                let reusable = match &**image {
+                    ImageMem::Shm(shm) => shm.len >= size,
+                    ImageMem::Heap(_) => false,
+                };
 
-        let depth = unsafe { (self.xlib.XDefaultDepthOfScreen)(self.x_scrn) };
-        debug!("DefaultDepthOfScreen = {}", depth);
-        assert_ne!(depth, 0);
+                if !reusable {
+                    match unsafe { ShmImage::new(self.xlib, xext, self.x_dpy, size) } {
+                        Ok(shm) => **image = ImageMem::Shm(shm),
+                        Err(e) => {
+                            debug!("Falling back to `XPutImage` because MIT-SHM failed: {}", e);
+                            self.xext.set(None);
+                            **image =
+                                ImageMem::Heap(Buffer::from_size_align(1, self.align).unwrap());
+                        }
+                    }
+                }
+            }
 
-        // TODO: Probably we need this sometime
-        let _ = depth;
+            if let ImageMem::Heap(buffer) = &mut **image {
+                buffer.resize(size)?;
+            }
+        }
 
-        let mut image = self.image.borrow_mut();
-        image.resize(size);
+        if let Some(present) = &self.present {
+            unsafe {
+                let trap = ErrorTrap::new(self.xlib, self.x_dpy);
+                present.recreate_pixmaps(self.xlib, self.x_dpy, self.x_wnd, extent, &self.visual);
+                trap.finish()?;
+            }
+        }
 
         self.image_info.set(ImageInfo {
             extent,
-            stride: extent[0] as usize * 4,
+            stride,
             format,
+            scale,
         });
+        self.needs_full_update.set(true);
+
+        Ok(())
     }
 
     pub fn supported_formats(&self) -> impl Iterator<Item = Format> + '_ {
-        [Format::Argb8888, Format::Xrgb8888].iter().cloned()
+        self.formats.iter().cloned()
     }
 
     pub fn image_info(&self) -> ImageInfo {
@@ -109,35 +360,217 @@ impl SurfaceImpl {
     }
 
     pub fn num_images(&self) -> usize {
-        1
+        self.images.len()
     }
 
     pub fn does_preserve_image(&self) -> bool {
         true
     }
 
-    pub fn poll_next_image(&self) -> Option<usize> {
-        Some(0)
+    pub fn honors_scale(&self) -> bool {
+        false
+    }
+
+    pub fn poll_next_image(&self, filter: &dyn Fn(usize) -> bool) -> Option<usize> {
+        let state = match &self.present {
+            Some(present) => &present.state,
+            None => return Some(0).filter(|&i| filter(i)),
+        };
+
+        let result = state.next_image(filter);
+
+        if result.is_none() {
+            trace!(
+                "{:?}: No swapchain image is available. Enabling `ready_cb`.",
+                state.wnd_id
+            );
+            state.enable_ready_cb.set(true);
+        }
+
+        result
+    }
+
+    pub fn lock_image(&self, i: usize) -> Result<impl DerefMut<Target = [u8]> + '_, SurfaceError> {
+        let image = self.image(i)?;
+        let image = image
+            .try_borrow_mut()
+            .map_err(|_| SurfaceError::ImageLocked)?;
+        Ok(OwningRefMut::new(image).map_mut(|p| &mut **p))
     }
 
-    pub fn lock_image(&self, i: usize) -> impl Deref<Target = [u8]> + DerefMut + '_ {
-        assert_eq!(i, 0);
-        OwningRefMut::new(self.image.borrow_mut()).map_mut(|p| &mut **p)
+    pub fn set_destination_size(&self, _size: Option<[u32; 2]>) -> Result<(), SurfaceError> {
+        Err(SurfaceError::BackendUnavailable("`set_destination_size`"))
     }
 
-    pub fn present_image(&self, i: usize) {
-        assert_eq!(i, 0);
+    pub fn set_opaque_region(&self, _region: &[Rect]) {}
+
+    pub fn poll_presentation_feedback(&self) -> Option<PresentationFeedback> {
+        self.feedback.pop()
+    }
+
+    pub fn present_image(&self, i: usize, damage: Option<&[Rect]>) -> Result<(), SurfaceError> {
+        let image = self.image(i)?;
 
         let image_info = self.image_info.get();
-        let image = self
-            .image
-            .try_borrow()
-            .expect("the image is currently locked");
+        let image = image
+            .try_borrow_mut()
+            .map_err(|_| SurfaceError::ImageLocked)?;
+
+        if image_info.extent[0] == 0 {
+            return Err(SurfaceError::NotInitialized);
+        }
+
+        let full_update = self.needs_full_update.replace(false);
+        let damage = if full_update { None } else { damage };
+
+        // The regions to send to the drawable
+        let rects: Vec<Rect> = match &self.present {
+            Some(present) => {
+                // The pixmap lags behind by the frames presented through the
+                // other pixmaps. The contents of new pixmaps are undefined,
+                // but `needs_full_update` covers them with a full damage.
+                let mut tracker = present.damage.borrow_mut();
+                tracker.update_with_image_info(&image_info, present.state.pixmaps.len(), true);
+                let region: Vec<Rect> = clip_damage(damage, image_info.extent).collect();
+                tracker.add_damage(&region);
+                let rects = tracker.image_damage(i).to_vec();
+                tracker.mark_painted(i);
+                rects
+            }
+            None => clip_damage(damage, image_info.extent).collect(),
+        };
+
+        let drawable = match &self.present {
+            Some(present) => present.state.pixmaps[i].get(),
+            None => self.x_wnd,
+        };
+
+        // Catch errors such as `BadDrawable` (the window was destroyed)
+        // instead of letting them terminate the process
+        let trap = unsafe { ErrorTrap::new(self.xlib, self.x_dpy) };
+
+        if let Some(xext) = self.shape_xext {
+            if !image_info.format.has_alpha() {
+                // Reset the window shape
+                if full_update {
+                    unsafe {
+                        (xext.XShapeCombineMask)(
+                            self.x_dpy,
+                            self.x_wnd,
+                            xext::ShapeBounding,
+                            0,
+                            0,
+                            0, // `None`
+                            xext::ShapeSet,
+                        );
+                    }
+                }
+            } else if let Some(damage) = damage {
+                // Only rescan the damaged regions. Cut them out of the current
+                // shape and add back their opaque parts.
+                let mut damage_rects: Vec<xlib::XRectangle> =
+                    clip_damage(Some(damage), image_info.extent)
+                        .map(|rect| xlib::XRectangle {
+                            x: rect.origin[0] as _,
+                            y: rect.origin[1] as _,
+                            width: rect.extent[0] as _,
+                            height: rect.extent[1] as _,
+                        })
+                        .collect();
+                if !damage_rects.is_empty() {
+                    unsafe {
+                        (xext.XShapeCombineRectangles)(
+                            self.x_dpy,
+                            self.x_wnd,
+                            xext::ShapeBounding,
+                            0,
+                            0,
+                            damage_rects.as_mut_ptr(),
+                            damage_rects.len() as _,
+                            xext::ShapeSubtract,
+                            xlib::Unsorted,
+                        );
+                    }
+                }
+
+                for rect in clip_damage(Some(damage), image_info.extent) {
+                    let mut rects = shape_rects_from_alpha(&image, image_info.stride, rect);
+                    if rects.is_empty() {
+                        continue;
+                    }
+                    unsafe {
+                        (xext.XShapeCombineRectangles)(
+                            self.x_dpy,
+                            self.x_wnd,
+                            xext::ShapeBounding,
+                            0,
+                            0,
+                            rects.as_mut_ptr(),
+                            rects.len() as _,
+                            xext::ShapeUnion,
+                            xext::YXBanded,
+                        );
+                    }
+                }
+            } else {
+                let rect = Rect::new([0, 0], image_info.extent);
+                let mut rects = shape_rects_from_alpha(&image, image_info.stride, rect);
+                unsafe {
+                    (xext.XShapeCombineRectangles)(
+                        self.x_dpy,
+                        self.x_wnd,
+                        xext::ShapeBounding,
+                        0,
+                        0,
+                        rects.as_mut_ptr(),
+                        rects.len() as _,
+                        xext::ShapeSet,
+                        xext::YXBanded,
+                    );
+                }
+            }
+        }
 
-        // TODO: Use XShape to set the window shape based on alpha channel
-        //       <https://www.x.org/releases/X11R7.7/doc/xextproto/shape.html>
+        let direct = self.visual.native_formats().contains(&image_info.format);
+        let keep_alpha = !self.opaque && image_info.format.has_alpha();
+        let fills_alpha = self.visual.fills_alpha(image_info.format, self.opaque);
 
-        // TODO: See if this works on uncommon visuals
+        let mut convert_buf = self.convert_buf.borrow_mut();
+        let convert_stride = self.convert_stride.get();
+        let (data, bytes_per_line) = if fills_alpha {
+            // The window has an alpha channel, but the image shouldn't be
+            // translucent. The contents of the X channel are undefined, so
+            // replace them with opaque alpha values. This is done on a copy
+            // because the application may rely on the image being preserved.
+            let alpha_mask = self.visual.alpha_mask();
+            for &rect in &rects {
+                let x_range =
+                    rect.origin[0] as usize * 4..(rect.origin[0] + rect.extent[0]) as usize * 4;
+                for y in rect.origin[1] as usize..(rect.origin[1] + rect.extent[1]) as usize {
+                    let src = &image[y * image_info.stride..][x_range.clone()];
+                    let dst = &mut convert_buf[y * convert_stride..][x_range.clone()];
+                    dst.copy_from_slice(src);
+                    fill_alpha(dst, alpha_mask);
+                }
+            }
+
+            (convert_buf.as_ptr(), convert_stride)
+        } else if direct {
+            (image.as_ptr(), image_info.stride)
+        } else {
+            for &rect in &rects {
+                self.converter.convert_rect(
+                    &image,
+                    image_info.stride,
+                    &mut convert_buf,
+                    convert_stride,
+                    rect,
+                    keep_alpha,
+                );
+            }
+
+            (convert_buf.as_ptr(), convert_stride)
+        };
 
         unsafe {
             let mut x_image = xlib::XImage {
@@ -145,7 +578,7 @@ impl SurfaceImpl {
                 height: image_info.extent[1] as _,
                 xoffset: 0,
                 format: xlib::ZPixmap,
-                data: image.as_ptr() as *mut _,
+                data: data as *mut _,
                 byte_order: if cfg!(target_endian = "little") {
                     xlib::LSBFirst
                 } else {
@@ -154,31 +587,817 @@ impl SurfaceImpl {
                 bitmap_unit: 32,
                 bitmap_bit_order: xlib::LSBFirst,
                 bitmap_pad: 32,
-                depth: 24,
-                bytes_per_line: image_info.stride as _,
-                bits_per_pixel: 32,
-                red_mask: 0xff0000,
-                green_mask: 0xff00,
-                blue_mask: 0xff,
+                depth: self.visual.depth as _,
+                bytes_per_line: bytes_per_line as _,
+                bits_per_pixel: self.visual.bits_per_pixel as _,
+                red_mask: self.visual.masks[0] as _,
+                green_mask: self.visual.masks[1] as _,
+                blue_mask: self.visual.masks[2] as _,
                 ..std::mem::zeroed()
             };
 
             (self.xlib.XInitImage)(&mut x_image);
 
-            let x_gc = (self.xlib.XDefaultGCOfScreen)(self.x_scrn);
+            let x_gc = self.x_gc;
 
-            (self.xlib.XPutImage)(
+            // Clip the drawing to the updated regions
+            let mut clip_rects: Vec<xlib::XRectangle> = rects
+                .iter()
+                .map(|rect| xlib::XRectangle {
+                    x: rect.origin[0] as _,
+                    y: rect.origin[1] as _,
+                    width: rect.extent[0] as _,
+                    height: rect.extent[1] as _,
+                })
+                .collect();
+            (self.xlib.XSetClipRectangles)(
                 self.x_dpy,
-                self.x_wnd,
                 x_gc,
-                &mut x_image,
-                0,
-                0,
                 0,
                 0,
-                image_info.extent[0] as _,
-                image_info.extent[1] as _,
+                clip_rects.as_mut_ptr(),
+                clip_rects.len() as _,
+                xlib::Unsorted,
             );
+
+            match &*image {
+                ImageMem::Shm(shm) if direct && !fills_alpha => {
+                    let xext = shm.xext;
+
+                    // `XShmPutImage` finds the segment through `obdata`
+                    let mut info = shm.info;
+                    x_image.obdata = &mut info as *mut XShmSegmentInfo as *mut _;
+
+                    for rect in &rects {
+                        (xext.XShmPutImage)(
+                            self.x_dpy,
+                            drawable,
+                            x_gc,
+                            &mut x_image,
+                            rect.origin[0] as _,
+                            rect.origin[1] as _,
+                            rect.origin[0] as _,
+                            rect.origin[1] as _,
+                            rect.extent[0] as _,
+                            rect.extent[1] as _,
+                            xlib::False,
+                        );
+                    }
+                }
+                _ => {
+                    // Split the data into pieces that fit in the maximum
+                    // request length. (Xlib would split an oversized
+                    // request by repeatedly halving it and copying the
+                    // halves.)
+                    let bytes_per_pixel = self.visual.bits_per_pixel as usize / 8;
+                    let pieces = rects.iter().flat_map(|&rect| {
+                        put_image_pieces(rect, bytes_per_pixel, self.max_request_len)
+                    });
+
+                    for piece in pieces {
+                        (self.xlib.XPutImage)(
+                            self.x_dpy,
+                            drawable,
+                            x_gc,
+                            &mut x_image,
+                            piece.origin[0] as _,
+                            piece.origin[1] as _,
+                            piece.origin[0] as _,
+                            piece.origin[1] as _,
+                            piece.extent[0] as _,
+                            piece.extent[1] as _,
+                        );
+                    }
+                }
+            }
+
+            if let Some(present) = &self.present {
+                present.present_pixmap(self.xlib, self.x_dpy, self.x_wnd, i);
+            }
+        }
+
+        // Wait until the server processes the requests. This also lets the
+        // application safely write the next frame into the MIT-SHM segment,
+        // which the server reads asynchronously.
+        let result = unsafe { trap.finish() };
+
+        if let (Err(_), Some(present)) = (&result, &self.present) {
+            // We won't receive `PresentIdleNotify` or `PresentCompleteNotify`
+            present.state.busy[i].set(false);
+            present.state.frame_pending.set(false);
+        }
+
+        if result.is_ok() {
+            self.feedback.push_estimated(i);
+        }
+
+        result
+    }
+
+    /// Get the swapchain image at index `i`, checking that its pixmap is not
+    /// in use by the X server.
+    fn image(&self, i: usize) -> Result<&RefCell<ImageMem>, SurfaceError> {
+        let image = self.images.get(i).ok_or(SurfaceError::BadImageIndex(i))?;
+
+        if let Some(present) = &self.present {
+            if present.state.busy[i].get() {
+                return Err(SurfaceError::ImageBusy);
+            }
+        }
+
+        Ok(image)
+    }
+}
+
+impl Drop for SurfaceImpl {
+    fn drop(&mut self) {
+        unsafe {
+            (self.xlib.XFreeGC)(self.x_dpy, self.x_gc);
+        }
+        if let Some(present) = &self.present {
+            unsafe {
+                present.destroy(self.xlib, self.x_dpy, self.x_wnd);
+            }
         }
     }
 }
+
+/// A swapchain made of pixmaps presented by the Present extension.
+struct PresentChain {
+    xpresent: &'static Xpresent,
+    state: Rc<PresentState>,
+    /// Tracks the regions where each pixmap is out-of-date.
+    damage: RefCell<DamageTracker>,
+}
+
+/// The part of `PresentChain` updated by Present events.
+struct PresentState {
+    x_dpy: *mut xlib::Display,
+    /// The event context ID returned by `XPresentSelectInput`.
+    eid: u32,
+    wnd_id: WindowId,
+    ready_cb: Rc<ReadyCb>,
+    /// `Config::vsync`. If `true`, presentations are synchronized to vertical
+    /// blanks, and we don't return a swapchain image until the last one is
+    /// complete.
+    vsync: bool,
+    /// The pixmaps backing the swapchain images. `0` means not allocated.
+    pixmaps: Box<[Cell<xlib::Pixmap>]>,
+    /// `true` for each swapchain image whose pixmap might be in use by the X
+    /// server.
+    busy: Box<[Cell<bool>]>,
+    /// The serial number of the last `PresentPixmap` request.
+    serial: Cell<u32>,
+    /// `true` if we are waiting for `PresentCompleteNotify` for `serial`.
+    frame_pending: Cell<bool>,
+    /// If `true`, the event handlers will call `ready_cb` when a swapchain
+    /// image becomes available.
+    enable_ready_cb: Cell<bool>,
+}
+
+impl PresentChain {
+    unsafe fn new(
+        xlib: &xlib::Xlib,
+        xpresent: &'static Xpresent,
+        x_dpy: *mut xlib::Display,
+        x_wnd: c_ulong,
+        wnd_id: WindowId,
+        context: &ContextImpl,
+        config: &Config,
+    ) -> Option<Self> {
+        let mut opcode = 0;
+        if (xpresent.XPresentQueryExtension)(x_dpy, &mut opcode, &mut 0, &mut 0) == 0 {
+            return None;
+        }
+
+        // `XPresentQueryExtension` has registered `libXpresent`'s event
+        // converter. Wrap it to intercept the events.
+        install_present_hook(xlib, x_dpy, opcode);
+
+        let eid = (xpresent.XPresentSelectInput)(
+            x_dpy,
+            x_wnd,
+            xext::PresentCompleteNotifyMask | xext::PresentIdleNotifyMask,
+        );
+
+        let num_images = config.image_count.max(1);
+        let state = Rc::new(PresentState {
+            x_dpy,
+            eid: eid as u32,
+            wnd_id,
+            ready_cb: Rc::clone(&context.ready_cb),
+            vsync: config.vsync,
+            pixmaps: (0..num_images).map(|_| Cell::new(0)).collect(),
+            busy: (0..num_images).map(|_| Cell::new(false)).collect(),
+            serial: Cell::new(0),
+            frame_pending: Cell::new(false),
+            enable_ready_cb: Cell::new(false),
+        });
+
+        PRESENT_STATES.with(|states| {
+            let mut states = states.borrow_mut();
+            states.retain(|state| state.upgrade().is_some());
+            states.push(Rc::downgrade(&state));
+        });
+
+        Some(Self {
+            xpresent,
+            state,
+            damage: RefCell::new(DamageTracker::new()),
+        })
+    }
+
+    unsafe fn recreate_pixmaps(
+        &self,
+        xlib: &xlib::Xlib,
+        x_dpy: *mut xlib::Display,
+        x_wnd: c_ulong,
+        extent: [u32; 2],
+        visual: &VisualLayout,
+    ) {
+        self.free_pixmaps(xlib, x_dpy);
+
+        for (pixmap, busy) in self.state.pixmaps.iter().zip(self.state.busy.iter()) {
+            pixmap.set((xlib.XCreatePixmap)(
+                x_dpy,
+                x_wnd,
+                extent[0],
+                extent[1],
+                visual.depth,
+            ));
+            busy.set(false);
+        }
+    }
+
+    unsafe fn free_pixmaps(&self, xlib: &xlib::Xlib, x_dpy: *mut xlib::Display) {
+        for pixmap in self.state.pixmaps.iter() {
+            // The X server keeps the pixmap until it's done with it
+            match pixmap.replace(0) {
+                0 => {}
+                x_pixmap => {
+                    (xlib.XFreePixmap)(x_dpy, x_pixmap);
+                }
+            }
+        }
+    }
+
+    /// Present the pixmap of the swapchain image `i`.
+    unsafe fn present_pixmap(
+        &self,
+        xlib: &xlib::Xlib,
+        x_dpy: *mut xlib::Display,
+        x_wnd: c_ulong,
+        i: usize,
+    ) {
+        let state = &self.state;
+        let serial = state.serial.get().wrapping_add(1);
+        state.serial.set(serial);
+        state.busy[i].set(true);
+        state.frame_pending.set(state.vsync);
+
+        trace!(
+            "{:?}: Presenting swapchain image {} (serial = {})",
+            state.wnd_id,
+            i,
+            serial
+        );
+
+        (self.xpresent.XPresentPixmap)(
+            x_dpy,
+            x_wnd,
+            state.pixmaps[i].get(),
+            serial,
+            0, // valid: the entire pixmap
+            0, // update: the entire pixmap
+            0,
+            0,
+            0, // target_crtc: chosen by the server
+            0, // wait_fence
+            0, // idle_fence
+            if state.vsync {
+                0
+            } else {
+                xext::PresentOptionAsync
+            },
+            0, // target_msc: as soon as possible
+            0,
+            0,
+            null_mut(),
+            0,
+        );
+
+        // Send the request now. The events are delivered through
+        // `present_cookie_hook` when Xlib reads them.
+        (xlib.XFlush)(x_dpy);
+    }
+
+    unsafe fn destroy(&self, xlib: &xlib::Xlib, x_dpy: *mut xlib::Display, x_wnd: c_ulong) {
+        (self.xpresent.XPresentFreeInput)(x_dpy, x_wnd, self.state.eid as _);
+        self.free_pixmaps(xlib, x_dpy);
+    }
+}
+
+impl PresentState {
+    /// Get the index of the first available image for which `filter` returns
+    /// `true`.
+    fn next_image(&self, filter: &dyn Fn(usize) -> bool) -> Option<usize> {
+        if self.frame_pending.get() {
+            // Wait until the last presentation is complete
+            return None;
+        }
+        (0..self.busy.len()).find(|&i| !self.busy[i].get() && filter(i))
+    }
+
+    /// Call `ready_cb` if the application wants to receive a notification and
+    /// a swapchain image is now available.
+    fn notify_ready(&self) {
+        if self.enable_ready_cb.get() && self.next_image(&|_| true).is_some() {
+            self.enable_ready_cb.set(false);
+            trace!("Calling `ready_cb`");
+            (self.ready_cb)(self.wnd_id);
+        }
+    }
+
+    fn handle_idle_notify(&self, x_pixmap: xlib::Pixmap) {
+        if let Some(i) = self.pixmaps.iter().position(|p| p.get() == x_pixmap) {
+            trace!("{:?}: Swapchain image {} is now idle", self.wnd_id, i);
+            self.busy[i].set(false);
+            self.notify_ready();
+        }
+    }
+
+    fn handle_complete_notify(&self, serial: u32) {
+        if serial == self.serial.get() {
+            self.frame_pending.set(false);
+            self.notify_ready();
+        }
+    }
+}
+
+/// Wrap `x_dpy`'s event converter for the Present extension (whose major
+/// opcode is `opcode`) with `present_cookie_hook`.
+unsafe fn install_present_hook(xlib: &xlib::Xlib, x_dpy: *mut xlib::Display, opcode: c_int) {
+    let mut procs = PRESENT_COOKIE_PROCS.lock().unwrap();
+    let old_proc = (xlib.XESetWireToEventCookie)(x_dpy, opcode, Some(present_cookie_hook));
+
+    // Don't trust an existing entry. The display it was made for may have been
+    // closed, and `x_dpy` may be a new display at the same address.
+    let hook_addr = present_cookie_hook as CookieProc as usize;
+    if old_proc.map(|p| p as usize) == Some(hook_addr) {
+        // Already installed on this display
+        return;
+    }
+
+    procs.retain(|&(dpy, _)| dpy != x_dpy as usize);
+    procs.push((x_dpy as usize, old_proc));
+}
+
+/// Converts a Present event using `libXpresent`'s converter and forwards it
+/// to the `PresentState` it's directed to.
+///
+/// Xlib calls this whenever it reads an event from the connection, which
+/// might happen inside any Xlib call on the display (including ours). The
+/// display may be locked, so this must not call Xlib functions.
+unsafe extern "C" fn present_cookie_hook(
+    x_dpy: *mut xlib::Display,
+    cookie: *mut xlib::XGenericEventCookie,
+    wire: *mut xlib::xEvent,
+) -> c_int {
+    let old_proc = PRESENT_COOKIE_PROCS.lock().ok().and_then(|procs| {
+        procs
+            .iter()
+            .find(|&&(dpy, _)| dpy == x_dpy as usize)
+            .and_then(|&(_, old_proc)| old_proc)
+    });
+    let ret = match old_proc {
+        Some(old_proc) => old_proc(x_dpy, cookie, wire),
+        None => xlib::False,
+    };
+    if ret == xlib::False || (*cookie).data.is_null() {
+        return ret;
+    }
+
+    let cookie = &*cookie;
+    match cookie.evtype {
+        xext::PresentIdleNotify => {
+            let event = &*(cookie.data as *const xext::XPresentIdleNotifyEvent);
+            if let Some(state) = find_present_state(x_dpy, event.eid) {
+                state.handle_idle_notify(event.pixmap);
+            }
+        }
+        xext::PresentCompleteNotify => {
+            let event = &*(cookie.data as *const xext::XPresentCompleteNotifyEvent);
+            if event.kind == xext::PresentCompleteKindPixmap {
+                if let Some(state) = find_present_state(x_dpy, event.eid) {
+                    state.handle_complete_notify(event.serial_number);
+                }
+            }
+        }
+        _ => {}
+    }
+
+    ret
+}
+
+/// Find the `PresentState` for the event context `eid`. Always returns `None`
+/// on a thread other than the one owning the surfaces.
+fn find_present_state(x_dpy: *mut xlib::Display, eid: u32) -> Option<Rc<PresentState>> {
+    PRESENT_STATES
+        .try_with(|states| {
+            let states = states.try_borrow().ok()?;
+            states
+                .iter()
+                .filter_map(Weak::upgrade)
+                .find(|state| state.x_dpy == x_dpy && state.eid == eid)
+        })
+        .ok()
+        .and_then(|state| state)
+}
+
+/// Get the pixel layout of a window having the attributes `x_wnd_attrs`.
+unsafe fn window_visual_layout(
+    xlib: &xlib::Xlib,
+    x_dpy: *mut xlib::Display,
+    x_wnd_attrs: &xlib::XWindowAttributes,
+) -> Result<VisualLayout, SurfaceError> {
+    let visual = x_wnd_attrs.visual;
+    if visual.is_null() {
+        return Err(SurfaceError::Platform(
+            "the window has no visual".to_owned(),
+        ));
+    }
+    let visual = &*visual;
+    let depth = x_wnd_attrs.depth as u32;
+
+    // Find the number of bits per pixel for the depth
+    let mut count = 0;
+    let pixmap_formats = (xlib.XListPixmapFormats)(x_dpy, &mut count);
+    if pixmap_formats.is_null() {
+        return Err(SurfaceError::Platform(
+            "XListPixmapFormats failed".to_owned(),
+        ));
+    }
+    let bits_per_pixel = from_raw_parts(pixmap_formats, count as usize)
+        .iter()
+        .find(|f| f.depth as u32 == depth)
+        .map(|f| f.bits_per_pixel as u32);
+    (xlib.XFree)(pixmap_formats as *mut _);
+
+    VisualLayout::new(
+        visual.class,
+        depth,
+        bits_per_pixel,
+        [
+            visual.red_mask as u32,
+            visual.green_mask as u32,
+            visual.blue_mask as u32,
+        ],
+    )
+}
+
+/// Compute the region in `rect` where alpha values are at least 50% as
+/// YX-banded rectangles. `image` is a 32-bit image having an alpha channel,
+/// whose most significant bit is the MSB of the alpha value.
+fn shape_rects_from_alpha(image: &[u8], stride: usize, rect: Rect) -> Vec<xlib::XRectangle> {
+    let [x_start, y_start] = rect.origin;
+    let [x_end, y_end] = [x_start + rect.extent[0], y_start + rect.extent[1]];
+    let mut rects: Vec<xlib::XRectangle> = Vec::new();
+
+    // The rectangles in `rects[band_start..]` form the last band
+    let mut band_start = 0;
+
+    for y in y_start..y_end {
+        let row = &image[y as usize * stride..][..x_end as usize * 4];
+        let is_opaque = |x: u32| {
+            let p = &row[x as usize * 4..][..4];
+            u32::from_ne_bytes([p[0], p[1], p[2], p[3]]) & 0x80000000 != 0
+        };
+
+        // Find the runs of opaque pixels
+        let row_start = rects.len();
+        let mut x = x_start;
+        while x < x_end {
+            while x < x_end && !is_opaque(x) {
+                x += 1;
+            }
+            let start = x;
+            while x < x_end && is_opaque(x) {
+                x += 1;
+            }
+            if x > start {
+                rects.push(xlib::XRectangle {
+                    x: start as _,
+                    y: y as _,
+                    width: (x - start) as _,
+                    height: 1,
+                });
+            }
+        }
+
+        // Extend the last band if the runs are identical
+        let (band, row) = rects[band_start..].split_at_mut(row_start - band_start);
+        let same_runs = band.len() == row.len()
+            && band
+                .iter()
+                .zip(row.iter())
+                .all(|(a, b)| a.x == b.x && a.width == b.width);
+
+        if same_runs {
+            for rect in band.iter_mut() {
+                rect.height += 1;
+            }
+            rects.truncate(row_start);
+        } else {
+            band_start = row_start;
+        }
+    }
+
+    rects
+}
+
+/// The backing store of a swapchain image.
+enum ImageMem {
+    Heap(Buffer),
+    Shm(ShmImage),
+}
+
+impl Deref for ImageMem {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            ImageMem::Heap(buffer) => buffer,
+            ImageMem::Shm(shm) => unsafe { from_raw_parts(shm.info.shmaddr as *const u8, shm.len) },
+        }
+    }
+}
+
+impl DerefMut for ImageMem {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            ImageMem::Heap(buffer) => buffer,
+            ImageMem::Shm(shm) => unsafe {
+                from_raw_parts_mut(shm.info.shmaddr as *mut u8, shm.len)
+            },
+        }
+    }
+}
+
+/// A System V shared memory segment attached to the X server.
+struct ShmImage {
+    xlib: &'static xlib::Xlib,
+    xext: &'static Xext,
+    x_dpy: *mut xlib::Display,
+    info: XShmSegmentInfo,
+    len: usize,
+}
+
+impl ShmImage {
+    unsafe fn new(
+        xlib: &'static xlib::Xlib,
+        xext: &'static Xext,
+        x_dpy: *mut xlib::Display,
+        len: usize,
+    ) -> Result<Self, SurfaceError> {
+        let shmid = libc::shmget(libc::IPC_PRIVATE, len, libc::IPC_CREAT | 0o600);
+        if shmid < 0 {
+            return Err(SurfaceError::Shm(std::io::Error::last_os_error()));
+        }
+
+        let shmaddr = libc::shmat(shmid, std::ptr::null(), 0);
+
+        if shmaddr as isize == -1 {
+            let e = std::io::Error::last_os_error();
+            libc::shmctl(shmid, libc::IPC_RMID, null_mut());
+            return Err(SurfaceError::Shm(e));
+        }
+
+        let mut info = XShmSegmentInfo {
+            shmseg: 0,
+            shmid,
+            shmaddr: shmaddr as *mut _,
+            readOnly: xlib::True,
+        };
+
+        // `XShmAttach` fails with `BadAccess` if the server can't access the
+        // segment (e.g., the server is in a different host or container).
+        let trap = ErrorTrap::new(xlib, x_dpy);
+        let ok = (xext.XShmAttach)(x_dpy, &mut info);
+
+        // Wait until the server has attached the segment before marking it
+        // for deletion. Some platforms (e.g., the BSDs) don't allow attaching
+        // a segment marked for deletion. The segment is destroyed when the
+        // last process (this process or the X server) detaches it.
+        (xlib.XSync)(x_dpy, xlib::False);
+        libc::shmctl(shmid, libc::IPC_RMID, null_mut());
+
+        let result = trap.finish();
+
+        if ok == 0 || result.is_err() {
+            if let Err(e) = result {
+                debug!("XShmAttach failed: {}", e);
+            }
+            libc::shmdt(shmaddr);
+            return Err(SurfaceError::Platform(
+                "the X server could not attach the shared memory segment".to_owned(),
+            ));
+        }
+
+        Ok(Self {
+            xlib,
+            xext,
+            x_dpy,
+            info,
+            len,
+        })
+    }
+}
+
+impl Drop for ShmImage {
+    fn drop(&mut self) {
+        unsafe {
+            (self.xext.XShmDetach)(self.x_dpy, &mut self.info);
+            // Make sure the server has detached the segment before we do
+            (self.xlib.XSync)(self.x_dpy, xlib::False);
+            libc::shmdt(self.info.shmaddr as *const c_void);
+        }
+    }
+}
+
+/// Catches the X errors caused by the requests sent while it's active instead
+/// of passing them to the current error handler, which terminates the process
+/// by default.
+///
+/// Error traps can't be nested.
+struct ErrorTrap<'a> {
+    xlib: &'a xlib::Xlib,
+    x_dpy: *mut xlib::Display,
+}
+
+struct ErrorTrapState {
+    /// The `Display` address.
+    x_dpy: usize,
+    /// The serial number of the first request covered by the trap.
+    start_serial: c_ulong,
+    old_handler: ErrorHandler,
+    /// The first error caught by the trap.
+    error: Option<XErrorInfo>,
+}
+
+type ErrorHandler =
+    Option<unsafe extern "C" fn(*mut xlib::Display, *mut xlib::XErrorEvent) -> c_int>;
+
+#[derive(Debug, Clone, Copy)]
+struct XErrorInfo {
+    error_code: u8,
+    request_code: u8,
+    minor_code: u8,
+}
+
+impl<'a> ErrorTrap<'a> {
+    unsafe fn new(xlib: &'a xlib::Xlib, x_dpy: *mut xlib::Display) -> Self {
+        let mut state = ERROR_TRAP.lock().unwrap();
+        debug_assert!(state.is_none(), "error traps can't be nested");
+
+        let old_handler = (xlib.XSetErrorHandler)(Some(trap_error_handler));
+        *state = Some(ErrorTrapState {
+            x_dpy: x_dpy as usize,
+            start_serial: (xlib.XNextRequest)(x_dpy),
+            old_handler,
+            error: None,
+        });
+
+        Self { xlib, x_dpy }
+    }
+
+    /// Wait until the server processes the requests and return the first
+    /// error caused by them.
+    unsafe fn finish(self) -> Result<(), SurfaceError> {
+        (self.xlib.XSync)(self.x_dpy, xlib::False);
+
+        let error = ERROR_TRAP
+            .lock()
+            .unwrap()
+            .as_mut()
+            .and_then(|state| state.error.take());
+
+        match error {
+            Some(error) => {
+                let mut text = [0 as c_char; 256];
+                (self.xlib.XGetErrorText)(
+                    self.x_dpy,
+                    error.error_code as c_int,
+                    text.as_mut_ptr(),
+                    text.len() as c_int,
+                );
+                let text = CStr::from_ptr(text.as_ptr()).to_string_lossy();
+                Err(SurfaceError::Platform(format!(
+                    "X error: {} (request code {}.{})",
+                    text, error.request_code, error.minor_code
+                )))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for ErrorTrap<'_> {
+    fn drop(&mut self) {
+        if let Some(state) = ERROR_TRAP.lock().unwrap().take() {
+            unsafe {
+                (self.xlib.XSetErrorHandler)(state.old_handler);
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn trap_error_handler(
+    x_dpy: *mut xlib::Display,
+    event: *mut xlib::XErrorEvent,
+) -> c_int {
+    let event = &*event;
+    let old_handler = {
+        let mut state = match ERROR_TRAP.lock() {
+            Ok(state) => state,
+            Err(_) => return 0,
+        };
+        match &mut *state {
+            Some(state) if state.x_dpy == x_dpy as usize && event.serial >= state.start_serial => {
+                if state.error.is_none() {
+                    state.error = Some(XErrorInfo {
+                        error_code: event.error_code,
+                        request_code: event.request_code,
+                        minor_code: event.minor_code,
+                    });
+                }
+                return 0;
+            }
+            Some(state) => state.old_handler,
+            None => None,
+        }
+    };
+
+    // The error isn't ours
+    match old_handler {
+        Some(old_handler) => old_handler(x_dpy, event as *const _ as *mut _),
+        None => 0,
+    }
+}
+
+/// Check if MIT-SHM can be used with the connection `x_dpy`.
+unsafe fn is_shm_usable(xlib: &xlib::Xlib, xext: &Xext, x_dpy: *mut xlib::Display) -> bool {
+    if (xext.XShmQueryExtension)(x_dpy) == 0 {
+        return false;
+    }
+
+    // Shared memory is only accessible by a local server. The display name is
+    // in the form `[protocol/][host]:display[.screen]`.
+    let name = (xlib.XDisplayString)(x_dpy);
+    if name.is_null() {
+        return false;
+    }
+    let name = CStr::from_ptr(name).to_bytes();
+    let host = &name[..name.iter().rposition(|&c| c == b':').unwrap_or(0)];
+    let host = host.rsplit(|&c| c == b'/').next().unwrap_or(host);
+
+    host.is_empty() || host == b"unix"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shape_rects() {
+        // 4x3 image; `#` is opaque
+        //   .##.
+        //   .##.
+        //   #..#
+        let rows = [".##.", ".##.", "#..#"];
+        let image: Vec<u8> = rows
+            .iter()
+            .flat_map(|row| row.bytes())
+            .flat_map(|c| {
+                let pixel: u32 = if c == b'#' { 0xff000000 } else { 0x7fffffff };
+                pixel.to_ne_bytes().to_vec()
+            })
+            .collect();
+        let shape_rects = |rect| -> Vec<_> {
+            shape_rects_from_alpha(&image, 16, rect)
+                .iter()
+                .map(|r| (r.x, r.y, r.width, r.height))
+                .collect()
+        };
+
+        assert_eq!(
+            shape_rects(Rect::new([0, 0], [4, 3])),
+            [(1, 0, 2, 2), (0, 2, 1, 1), (3, 2, 1, 1)]
+        );
+
+        // Only the pixels in the given rectangle are scanned
+        assert_eq!(
+            shape_rects(Rect::new([2, 1], [2, 2])),
+            [(2, 1, 1, 1), (3, 2, 1, 1)]
+        );
+    }
+}
@@ -0,0 +1,490 @@
+//! X11 backend implemented on XCB, enabled by the `xcb` feature
+//!
+//! Unlike the Xlib backend, this backend doesn't use MIT-SHM, Present, or
+//! SHAPE. Images are sent by `PutImage` requests, whose errors are reported by
+//! `present_image`.
+use log::{debug, trace};
+use owning_ref::OwningRefMut;
+use std::{
+    cell::{Cell, RefCell},
+    fmt,
+    ops::DerefMut,
+    os::raw::{c_int, c_ulong, c_void},
+    ptr::{null, null_mut},
+    rc::Rc,
+    slice::from_raw_parts,
+};
+use winit::window::WindowId;
+
+use super::{
+    super::{
+        align::Align,
+        buffer::Buffer,
+        clip_damage,
+        feedback::{FeedbackQueue, PresentationFeedback},
+        Config, ContextBuilder, Format, ImageInfo, ReadyCb, Rect, SurfaceError,
+    },
+    xcbffi::*,
+    ximage::{fill_alpha, pad_row_len, put_image_pieces, PixelConverter, VisualLayout},
+};
+
+lazy_static::lazy_static! {
+    static ref XCB: Option<Xcb> = Xcb::open();
+}
+
+pub struct ContextImpl {
+    ready_cb: Rc<ReadyCb>,
+}
+
+impl fmt::Debug for ContextImpl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ContextImpl").finish()
+    }
+}
+
+impl ContextImpl {
+    pub fn new<T: 'static>(builder: ContextBuilder<'_, T>) -> Self {
+        Self {
+            ready_cb: Rc::new(builder.ready_cb),
+        }
+    }
+}
+
+pub struct SurfaceImpl {
+    xcb: &'static Xcb,
+    conn: *mut xcb_connection_t,
+    x_wnd: xcb_window_t,
+    x_gc: xcb_gcontext_t,
+    wnd_id: WindowId,
+    /// `present_image` completes synchronously, so this is called at its end
+    /// to report that the image is available again.
+    ready_cb: Rc<ReadyCb>,
+    /// The pixel layout of the window. `PutImage` data must have this layout.
+    visual: VisualLayout,
+    /// `true` if the server's image byte order differs from ours.
+    swap_bytes: bool,
+    /// The maximum request length in bytes.
+    max_request_len: usize,
+    /// Supported pixel formats. The formats not in
+    /// `visual.native_formats()` are converted by `converter`.
+    formats: Vec<Format>,
+    converter: PixelConverter,
+    /// The converted image and its stride. Only used if the image format
+    /// doesn't match the window's pixel layout.
+    convert_buf: RefCell<Buffer>,
+    convert_stride: Cell<usize>,
+    /// The data of the `PutImage` request being built.
+    request_buf: RefCell<Vec<u8>>,
+    /// `Config::opaque`.
+    opaque: bool,
+    image_info: Cell<ImageInfo>,
+    image: RefCell<Buffer>,
+    scanline_align: Align,
+    /// `true` if the next presentation has to send the entire image regardless
+    /// of a damage region because the window was resized.
+    needs_full_update: Cell<bool>,
+    feedback: FeedbackQueue,
+}
+
+impl fmt::Debug for SurfaceImpl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SurfaceImpl").finish()
+    }
+}
+
+impl SurfaceImpl {
+    pub unsafe fn new(
+        conn: *mut c_void,
+        x_wnd: c_ulong,
+        wnd_id: WindowId,
+        context: &ContextImpl,
+        config: &Config,
+        scanline_align: Align,
+    ) -> Result<Self, SurfaceError> {
+        let xcb = XCB
+            .as_ref()
+            .ok_or(SurfaceError::BackendUnavailable("libxcb"))?;
+        let conn = conn as *mut xcb_connection_t;
+        let x_wnd = x_wnd as xcb_window_t;
+
+        let setup = (xcb.xcb_get_setup)(conn);
+        if setup.is_null() {
+            return Err(SurfaceError::Platform("xcb_get_setup failed".to_owned()));
+        }
+
+        // Get the window's visual
+        let cookie = (xcb.xcb_get_window_attributes)(conn, x_wnd);
+        let mut error = null_mut();
+        let reply = (xcb.xcb_get_window_attributes_reply)(conn, cookie, &mut error);
+        if reply.is_null() {
+            return Err(take_error("GetWindowAttributes", error));
+        }
+        let visual_id = (*reply).visual;
+        libc::free(reply as *mut c_void);
+
+        let visual = window_visual_layout(xcb, setup, visual_id)?;
+        debug!("Window visual = {:?}", visual);
+
+        // `Argb8888` and `Xrgb8888` are always supported through conversion
+        let mut formats = visual.native_formats().to_vec();
+        for &format in &[Format::Argb8888, Format::Xrgb8888] {
+            if !formats.contains(&format) {
+                formats.push(format);
+            }
+        }
+
+        let our_byte_order = if cfg!(target_endian = "little") {
+            XCB_IMAGE_ORDER_LSB_FIRST
+        } else {
+            XCB_IMAGE_ORDER_MSB_FIRST
+        };
+        let swap_bytes = (*setup).image_byte_order != our_byte_order;
+
+        // This takes BIG-REQUESTS into account
+        let max_request_len = (xcb.xcb_get_maximum_request_length)(conn) as usize * 4;
+        debug!("Maximum request length = {} bytes", max_request_len);
+
+        let x_gc = (xcb.xcb_generate_id)(conn);
+        let cookie = (xcb.xcb_create_gc_checked)(conn, x_gc, x_wnd, 0, null());
+        let error = (xcb.xcb_request_check)(conn, cookie);
+        if !error.is_null() {
+            return Err(take_error("CreateGC", error));
+        }
+
+        Ok(Self {
+            xcb,
+            conn,
+            x_wnd,
+            x_gc,
+            wnd_id,
+            ready_cb: Rc::clone(&context.ready_cb),
+            visual,
+            swap_bytes,
+            max_request_len,
+            formats,
+            converter: PixelConverter::new(visual),
+            convert_buf: RefCell::new(Buffer::from_size_align(1, config.align).unwrap()),
+            convert_stride: Cell::new(0),
+            request_buf: RefCell::new(Vec::new()),
+            opaque: config.opaque,
+            image_info: Cell::new(ImageInfo::default()),
+            image: RefCell::new(Buffer::from_size_align(1, config.align).unwrap()),
+            scanline_align,
+            needs_full_update: Cell::new(true),
+            feedback: FeedbackQueue::new(),
+        })
+    }
+
+    pub fn update_surface(
+        &self,
+        extent: [u32; 2],
+        format: Format,
+        scale: u32,
+    ) -> Result<(), SurfaceError> {
+        if extent[0] == 0 || extent[1] == 0 {
+            return Err(SurfaceError::ZeroExtent);
+        }
+        // `PutImage` takes 16-bit coordinates
+        if extent[0] > i16::MAX as u32 || extent[1] > i16::MAX as u32 {
+            return Err(SurfaceError::ExtentTooLarge);
+        }
+
+        use std::convert::TryInto;
+        let extent_usize: [usize; 2] = [
+            extent[0]
+                .try_into()
+                .map_err(|_| SurfaceError::ExtentTooLarge)?,
+            extent[1]
+                .try_into()
+                .map_err(|_| SurfaceError::ExtentTooLarge)?,
+        ];
+
+        let stride = extent_usize[0]
+            .checked_mul(format.bytes_per_pixel())
+            .and_then(|x| self.scanline_align.align_up(x))
+            .ok_or(SurfaceError::ExtentTooLarge)?;
+
+        let size = stride
+            .checked_mul(extent_usize[1])
+            .ok_or(SurfaceError::ExtentTooLarge)?;
+
+        let mut image = self
+            .image
+            .try_borrow_mut()
+            .map_err(|_| SurfaceError::ImageLocked)?;
+
+        if self.visual.native_formats().contains(&format) {
+            self.convert_buf.borrow_mut().resize(1)?;
+        } else {
+            // Allocate a buffer for the converted image
+            let convert_stride = extent_usize[0]
+                .checked_mul(self.visual.bits_per_pixel as usize / 8)
+                .ok_or(SurfaceError::ExtentTooLarge)?;
+            let convert_size = convert_stride
+                .checked_mul(extent_usize[1])
+                .ok_or(SurfaceError::ExtentTooLarge)?;
+            self.convert_buf.borrow_mut().resize(convert_size)?;
+            self.convert_stride.set(convert_stride);
+        }
+
+        image.resize(size)?;
+
+        self.image_info.set(ImageInfo {
+            extent,
+            stride,
+            format,
+            scale,
+        });
+        self.needs_full_update.set(true);
+
+        Ok(())
+    }
+
+    pub fn supported_formats(&self) -> impl Iterator<Item = Format> + '_ {
+        self.formats.iter().cloned()
+    }
+
+    pub fn image_info(&self) -> ImageInfo {
+        self.image_info.get()
+    }
+
+    pub fn num_images(&self) -> usize {
+        1
+    }
+
+    pub fn does_preserve_image(&self) -> bool {
+        true
+    }
+
+    pub fn honors_scale(&self) -> bool {
+        false
+    }
+
+    pub fn poll_next_image(&self, filter: &dyn Fn(usize) -> bool) -> Option<usize> {
+        Some(0).filter(|&i| filter(i))
+    }
+
+    pub fn lock_image(&self, i: usize) -> Result<impl DerefMut<Target = [u8]> + '_, SurfaceError> {
+        if i != 0 {
+            return Err(SurfaceError::BadImageIndex(i));
+        }
+        let image = self
+            .image
+            .try_borrow_mut()
+            .map_err(|_| SurfaceError::ImageLocked)?;
+        Ok(OwningRefMut::new(image).map_mut(|p| &mut **p))
+    }
+
+    pub fn set_destination_size(&self, _size: Option<[u32; 2]>) -> Result<(), SurfaceError> {
+        Err(SurfaceError::BackendUnavailable("`set_destination_size`"))
+    }
+
+    pub fn set_opaque_region(&self, _region: &[Rect]) {}
+
+    pub fn poll_presentation_feedback(&self) -> Option<PresentationFeedback> {
+        self.feedback.pop()
+    }
+
+    pub fn present_image(&self, i: usize, damage: Option<&[Rect]>) -> Result<(), SurfaceError> {
+        if i != 0 {
+            return Err(SurfaceError::BadImageIndex(i));
+        }
+
+        let image_info = self.image_info.get();
+        let image = self
+            .image
+            .try_borrow_mut()
+            .map_err(|_| SurfaceError::ImageLocked)?;
+
+        if image_info.extent[0] == 0 {
+            return Err(SurfaceError::NotInitialized);
+        }
+
+        let damage = if self.needs_full_update.replace(false) {
+            None
+        } else {
+            damage
+        };
+
+        let direct = self.visual.native_formats().contains(&image_info.format);
+        let keep_alpha = !self.opaque && image_info.format.has_alpha();
+
+        // The window has an alpha channel, but the image shouldn't be
+        // translucent. The contents of the X channel are undefined, so
+        // `put_rect` replaces them with opaque alpha values as it copies the
+        // pixels. The image itself is left intact because the application
+        // may rely on it being preserved.
+        let alpha_mask = if self.visual.fills_alpha(image_info.format, self.opaque) {
+            self.visual.alpha_mask()
+        } else {
+            0
+        };
+
+        let mut convert_buf = self.convert_buf.borrow_mut();
+        let (data, stride): (&[u8], usize) = if direct {
+            (&image, image_info.stride)
+        } else {
+            let convert_stride = self.convert_stride.get();
+            for rect in clip_damage(damage, image_info.extent) {
+                self.converter.convert_rect(
+                    &image,
+                    image_info.stride,
+                    &mut convert_buf,
+                    convert_stride,
+                    rect,
+                    keep_alpha,
+                );
+            }
+
+            (&convert_buf, convert_stride)
+        };
+
+        let bytes_per_pixel = self.visual.bits_per_pixel as usize / 8;
+        let mut cookies = Vec::new();
+        for rect in clip_damage(damage, image_info.extent) {
+            for piece in put_image_pieces(rect, bytes_per_pixel, self.max_request_len) {
+                cookies.push(unsafe { self.put_rect(data, stride, piece, alpha_mask) });
+            }
+        }
+
+        // Wait for the requests to be processed and report the first error
+        let mut result = Ok(());
+        for cookie in cookies {
+            let error = unsafe { (self.xcb.xcb_request_check)(self.conn, cookie) };
+            if !error.is_null() {
+                let e = unsafe { take_error("PutImage", error) };
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+
+        if result.is_ok() {
+            self.feedback.push_estimated(i);
+        }
+
+        drop((image, convert_buf));
+        trace!("Calling `ready_cb`");
+        (self.ready_cb)(self.wnd_id);
+
+        result
+    }
+
+    /// Send the pixels in `rect` of `data` to the window by a single
+    /// `PutImage` request, setting the bits `alpha_mask` of each pixel.
+    unsafe fn put_rect(
+        &self,
+        data: &[u8],
+        stride: usize,
+        rect: Rect,
+        alpha_mask: u32,
+    ) -> xcb_void_cookie_t {
+        let bytes_per_pixel = self.visual.bits_per_pixel as usize / 8;
+        let row_len = rect.extent[0] as usize * bytes_per_pixel;
+        let padded_row_len = pad_row_len(row_len);
+
+        let mut request_buf = self.request_buf.borrow_mut();
+        request_buf.clear();
+        request_buf.resize(padded_row_len * rect.extent[1] as usize, 0);
+
+        for (k, dst) in request_buf.chunks_exact_mut(padded_row_len).enumerate() {
+            let y = rect.origin[1] as usize + k;
+            let offset = y * stride + rect.origin[0] as usize * bytes_per_pixel;
+            let dst = &mut dst[..row_len];
+            dst.copy_from_slice(&data[offset..][..row_len]);
+
+            if alpha_mask != 0 {
+                fill_alpha(dst, alpha_mask);
+            }
+
+            if self.swap_bytes {
+                for pixel in dst.chunks_exact_mut(bytes_per_pixel) {
+                    pixel.reverse();
+                }
+            }
+        }
+
+        // XCB is done with `request_buf` when this returns
+        (self.xcb.xcb_put_image_checked)(
+            self.conn,
+            XCB_IMAGE_FORMAT_Z_PIXMAP,
+            self.x_wnd,
+            self.x_gc,
+            rect.extent[0] as u16,
+            rect.extent[1] as u16,
+            rect.origin[0] as i16,
+            rect.origin[1] as i16,
+            0,
+            self.visual.depth as u8,
+            request_buf.len() as u32,
+            request_buf.as_ptr(),
+        )
+    }
+}
+
+impl Drop for SurfaceImpl {
+    fn drop(&mut self) {
+        unsafe {
+            (self.xcb.xcb_free_gc)(self.conn, self.x_gc);
+            (self.xcb.xcb_flush)(self.conn);
+        }
+    }
+}
+
+/// Get the pixel layout of the visual `visual_id`.
+unsafe fn window_visual_layout(
+    xcb: &Xcb,
+    setup: *const xcb_setup_t,
+    visual_id: xcb_visualid_t,
+) -> Result<VisualLayout, SurfaceError> {
+    // Find the visual and the depth it belongs to
+    let mut found = None;
+    let mut screens = (xcb.xcb_setup_roots_iterator)(setup);
+    'screens: while screens.rem > 0 {
+        let mut depths = (xcb.xcb_screen_allowed_depths_iterator)(screens.data);
+        while depths.rem > 0 {
+            let visuals = from_raw_parts(
+                (xcb.xcb_depth_visuals)(depths.data),
+                (xcb.xcb_depth_visuals_length)(depths.data) as usize,
+            );
+            if let Some(visual) = visuals.iter().find(|v| v.visual_id == visual_id) {
+                found = Some(((*depths.data).depth, *visual));
+                break 'screens;
+            }
+            (xcb.xcb_depth_next)(&mut depths);
+        }
+        (xcb.xcb_screen_next)(&mut screens);
+    }
+
+    let (depth, visual) = found
+        .ok_or_else(|| SurfaceError::Platform("the window's visual was not found".to_owned()))?;
+
+    // Find the number of bits per pixel for the depth
+    let pixmap_formats = from_raw_parts(
+        (xcb.xcb_setup_pixmap_formats)(setup),
+        (xcb.xcb_setup_pixmap_formats_length)(setup) as usize,
+    );
+    let bits_per_pixel = pixmap_formats
+        .iter()
+        .find(|f| f.depth == depth)
+        .map(|f| u32::from(f.bits_per_pixel));
+
+    VisualLayout::new(
+        c_int::from(visual.class),
+        u32::from(depth),
+        bits_per_pixel,
+        [visual.red_mask, visual.green_mask, visual.blue_mask],
+    )
+}
+
+/// Convert an error returned by XCB to `SurfaceError` and free it.
+unsafe fn take_error(request: &str, error: *mut xcb_generic_error_t) -> SurfaceError {
+    let message = if error.is_null() {
+        format!("{} failed", request)
+    } else {
+        let code = (*error).error_code;
+        libc::free(error as *mut c_void);
+        format!("{} failed with error code {}", request, code)
+    };
+    SurfaceError::Platform(message)
+}
@@ -0,0 +1,335 @@
+//! XCB functions imported from `libxcb`.
+#![allow(non_camel_case_types)]
+use std::os::raw::{c_int, c_uint, c_void};
+
+use super::open_library;
+
+pub enum xcb_connection_t {}
+
+pub type xcb_window_t = u32;
+pub type xcb_drawable_t = u32;
+pub type xcb_gcontext_t = u32;
+pub type xcb_visualid_t = u32;
+
+pub const XCB_IMAGE_FORMAT_Z_PIXMAP: u8 = 2;
+pub const XCB_IMAGE_ORDER_LSB_FIRST: u8 = 0;
+pub const XCB_IMAGE_ORDER_MSB_FIRST: u8 = 1;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct xcb_void_cookie_t {
+    pub sequence: c_uint,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct xcb_get_window_attributes_cookie_t {
+    pub sequence: c_uint,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct xcb_generic_error_t {
+    pub response_type: u8,
+    pub error_code: u8,
+    pub sequence: u16,
+    pub resource_id: u32,
+    pub minor_code: u16,
+    pub major_code: u8,
+    pub pad0: u8,
+    pub pad: [u32; 5],
+    pub full_sequence: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct xcb_get_window_attributes_reply_t {
+    pub response_type: u8,
+    pub backing_store: u8,
+    pub sequence: u16,
+    pub length: u32,
+    pub visual: xcb_visualid_t,
+    pub class: u16,
+    pub bit_gravity: u8,
+    pub win_gravity: u8,
+    pub backing_planes: u32,
+    pub backing_pixel: u32,
+    pub save_under: u8,
+    pub map_is_installed: u8,
+    pub map_state: u8,
+    pub override_redirect: u8,
+    pub colormap: u32,
+    pub all_event_masks: u32,
+    pub your_event_mask: u32,
+    pub do_not_propagate_mask: u16,
+    pub pad0: [u8; 2],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct xcb_setup_t {
+    pub status: u8,
+    pub pad0: u8,
+    pub protocol_major_version: u16,
+    pub protocol_minor_version: u16,
+    pub length: u16,
+    pub release_number: u32,
+    pub resource_id_base: u32,
+    pub resource_id_mask: u32,
+    pub motion_buffer_size: u32,
+    pub vendor_len: u16,
+    pub maximum_request_length: u16,
+    pub roots_len: u8,
+    pub pixmap_formats_len: u8,
+    pub image_byte_order: u8,
+    pub bitmap_format_bit_order: u8,
+    pub bitmap_format_scanline_unit: u8,
+    pub bitmap_format_scanline_pad: u8,
+    pub min_keycode: u8,
+    pub max_keycode: u8,
+    pub pad1: [u8; 4],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct xcb_format_t {
+    pub depth: u8,
+    pub bits_per_pixel: u8,
+    pub scanline_pad: u8,
+    pub pad0: [u8; 5],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct xcb_screen_t {
+    pub root: xcb_window_t,
+    pub default_colormap: u32,
+    pub white_pixel: u32,
+    pub black_pixel: u32,
+    pub current_input_masks: u32,
+    pub width_in_pixels: u16,
+    pub height_in_pixels: u16,
+    pub width_in_millimeters: u16,
+    pub height_in_millimeters: u16,
+    pub min_installed_maps: u16,
+    pub max_installed_maps: u16,
+    pub root_visual: xcb_visualid_t,
+    pub backing_stores: u8,
+    pub save_unders: u8,
+    pub root_depth: u8,
+    pub allowed_depths_len: u8,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct xcb_screen_iterator_t {
+    pub data: *mut xcb_screen_t,
+    pub rem: c_int,
+    pub index: c_int,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct xcb_depth_t {
+    pub depth: u8,
+    pub pad0: u8,
+    pub visuals_len: u16,
+    pub pad1: [u8; 4],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct xcb_depth_iterator_t {
+    pub data: *mut xcb_depth_t,
+    pub rem: c_int,
+    pub index: c_int,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct xcb_visualtype_t {
+    pub visual_id: xcb_visualid_t,
+    pub class: u8,
+    pub bits_per_rgb_value: u8,
+    pub colormap_entries: u16,
+    pub red_mask: u32,
+    pub green_mask: u32,
+    pub blue_mask: u32,
+    pub pad0: [u8; 4],
+}
+
+/// Function pointers loaded from `libxcb`. The library is never unloaded.
+pub struct Xcb {
+    pub xcb_get_setup: unsafe extern "C" fn(*mut xcb_connection_t) -> *const xcb_setup_t,
+    pub xcb_setup_pixmap_formats: unsafe extern "C" fn(*const xcb_setup_t) -> *mut xcb_format_t,
+    pub xcb_setup_pixmap_formats_length: unsafe extern "C" fn(*const xcb_setup_t) -> c_int,
+    pub xcb_setup_roots_iterator: unsafe extern "C" fn(*const xcb_setup_t) -> xcb_screen_iterator_t,
+    pub xcb_screen_next: unsafe extern "C" fn(*mut xcb_screen_iterator_t),
+    pub xcb_screen_allowed_depths_iterator:
+        unsafe extern "C" fn(*const xcb_screen_t) -> xcb_depth_iterator_t,
+    pub xcb_depth_next: unsafe extern "C" fn(*mut xcb_depth_iterator_t),
+    pub xcb_depth_visuals: unsafe extern "C" fn(*const xcb_depth_t) -> *mut xcb_visualtype_t,
+    pub xcb_depth_visuals_length: unsafe extern "C" fn(*const xcb_depth_t) -> c_int,
+    pub xcb_get_window_attributes: unsafe extern "C" fn(
+        *mut xcb_connection_t,
+        xcb_window_t,
+    ) -> xcb_get_window_attributes_cookie_t,
+    pub xcb_get_window_attributes_reply:
+        unsafe extern "C" fn(
+            *mut xcb_connection_t,
+            xcb_get_window_attributes_cookie_t,
+            *mut *mut xcb_generic_error_t,
+        ) -> *mut xcb_get_window_attributes_reply_t,
+    pub xcb_generate_id: unsafe extern "C" fn(*mut xcb_connection_t) -> u32,
+    pub xcb_create_gc_checked: unsafe extern "C" fn(
+        *mut xcb_connection_t,
+        xcb_gcontext_t,
+        xcb_drawable_t,
+        u32,
+        *const c_void,
+    ) -> xcb_void_cookie_t,
+    pub xcb_free_gc:
+        unsafe extern "C" fn(*mut xcb_connection_t, xcb_gcontext_t) -> xcb_void_cookie_t,
+    pub xcb_put_image_checked: unsafe extern "C" fn(
+        *mut xcb_connection_t,
+        u8,
+        xcb_drawable_t,
+        xcb_gcontext_t,
+        u16,
+        u16,
+        i16,
+        i16,
+        u8,
+        u8,
+        u32,
+        *const u8,
+    ) -> xcb_void_cookie_t,
+    pub xcb_request_check:
+        unsafe extern "C" fn(*mut xcb_connection_t, xcb_void_cookie_t) -> *mut xcb_generic_error_t,
+    pub xcb_get_maximum_request_length: unsafe extern "C" fn(*mut xcb_connection_t) -> u32,
+    pub xcb_flush: unsafe extern "C" fn(*mut xcb_connection_t) -> c_int,
+}
+
+impl Xcb {
+    pub fn open() -> Option<Self> {
+        unsafe {
+            let sym = open_library(&[b"libxcb.so.1\0", b"libxcb.so\0"])?;
+
+            Some(Self {
+                xcb_get_setup: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(*mut xcb_connection_t) -> *const xcb_setup_t,
+                >(sym(b"xcb_get_setup\0")?),
+                xcb_setup_pixmap_formats: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(*const xcb_setup_t) -> *mut xcb_format_t,
+                >(sym(b"xcb_setup_pixmap_formats\0")?),
+                xcb_setup_pixmap_formats_length: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(*const xcb_setup_t) -> c_int,
+                >(sym(
+                    b"xcb_setup_pixmap_formats_length\0",
+                )?),
+                xcb_setup_roots_iterator: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(*const xcb_setup_t) -> xcb_screen_iterator_t,
+                >(sym(b"xcb_setup_roots_iterator\0")?),
+                xcb_screen_next: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(*mut xcb_screen_iterator_t),
+                >(sym(b"xcb_screen_next\0")?),
+                xcb_screen_allowed_depths_iterator: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(*const xcb_screen_t) -> xcb_depth_iterator_t,
+                >(sym(
+                    b"xcb_screen_allowed_depths_iterator\0",
+                )?),
+                xcb_depth_next: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(*mut xcb_depth_iterator_t),
+                >(sym(b"xcb_depth_next\0")?),
+                xcb_depth_visuals: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(*const xcb_depth_t) -> *mut xcb_visualtype_t,
+                >(sym(b"xcb_depth_visuals\0")?),
+                xcb_depth_visuals_length: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(*const xcb_depth_t) -> c_int,
+                >(sym(b"xcb_depth_visuals_length\0")?),
+                xcb_get_window_attributes: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(
+                        *mut xcb_connection_t,
+                        xcb_window_t,
+                    ) -> xcb_get_window_attributes_cookie_t,
+                >(sym(b"xcb_get_window_attributes\0")?),
+                xcb_get_window_attributes_reply: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(
+                        *mut xcb_connection_t,
+                        xcb_get_window_attributes_cookie_t,
+                        *mut *mut xcb_generic_error_t,
+                    )
+                        -> *mut xcb_get_window_attributes_reply_t,
+                >(sym(
+                    b"xcb_get_window_attributes_reply\0",
+                )?),
+                xcb_generate_id: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(*mut xcb_connection_t) -> u32,
+                >(sym(b"xcb_generate_id\0")?),
+                xcb_create_gc_checked: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(
+                        *mut xcb_connection_t,
+                        xcb_gcontext_t,
+                        xcb_drawable_t,
+                        u32,
+                        *const c_void,
+                    ) -> xcb_void_cookie_t,
+                >(sym(b"xcb_create_gc_checked\0")?),
+                xcb_free_gc: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(
+                        *mut xcb_connection_t,
+                        xcb_gcontext_t,
+                    ) -> xcb_void_cookie_t,
+                >(sym(b"xcb_free_gc\0")?),
+                xcb_put_image_checked: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(
+                        *mut xcb_connection_t,
+                        u8,
+                        xcb_drawable_t,
+                        xcb_gcontext_t,
+                        u16,
+                        u16,
+                        i16,
+                        i16,
+                        u8,
+                        u8,
+                        u32,
+                        *const u8,
+                    ) -> xcb_void_cookie_t,
+                >(sym(b"xcb_put_image_checked\0")?),
+                xcb_request_check: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(
+                        *mut xcb_connection_t,
+                        xcb_void_cookie_t,
+                    ) -> *mut xcb_generic_error_t,
+                >(sym(b"xcb_request_check\0")?),
+                xcb_get_maximum_request_length: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(*mut xcb_connection_t) -> u32,
+                >(sym(
+                    b"xcb_get_maximum_request_length\0",
+                )?),
+                xcb_flush: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(*mut xcb_connection_t) -> c_int,
+                >(sym(b"xcb_flush\0")?),
+            })
+        }
+    }
+}
@@ -0,0 +1,232 @@
+//! MIT-SHM and SHAPE extension functions imported from `libXext` and Present
+//! extension functions imported from `libXpresent`.
+#![allow(non_snake_case, non_upper_case_globals)]
+use std::os::raw::{c_char, c_int, c_uint, c_ulong, c_void};
+use x11_dl::xlib::{Bool, Display, Drawable, Pixmap, Window, XImage, XRectangle, GC, XID};
+
+use super::open_library;
+
+pub type ShmSeg = c_ulong;
+
+pub const ShapeSet: c_int = 0;
+pub const ShapeUnion: c_int = 1;
+pub const ShapeSubtract: c_int = 3;
+pub const ShapeBounding: c_int = 0;
+pub const YXBanded: c_int = 3;
+
+pub const PresentCompleteNotify: c_int = 1;
+pub const PresentIdleNotify: c_int = 2;
+pub const PresentCompleteNotifyMask: c_uint = 1 << 1;
+pub const PresentIdleNotifyMask: c_uint = 1 << 2;
+pub const PresentOptionAsync: u32 = 1 << 0;
+pub const PresentCompleteKindPixmap: u8 = 0;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct XShmSegmentInfo {
+    pub shmseg: ShmSeg,
+    pub shmid: c_int,
+    pub shmaddr: *mut c_char,
+    pub readOnly: Bool,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct XPresentIdleNotifyEvent {
+    pub type_: c_int,
+    pub serial: c_ulong,
+    pub send_event: Bool,
+    pub display: *mut Display,
+    pub extension: c_int,
+    pub evtype: c_int,
+    pub eid: u32,
+    pub window: Window,
+    pub serial_number: u32,
+    pub pixmap: Pixmap,
+    pub idle_fence: XID,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct XPresentCompleteNotifyEvent {
+    pub type_: c_int,
+    pub serial: c_ulong,
+    pub send_event: Bool,
+    pub display: *mut Display,
+    pub extension: c_int,
+    pub evtype: c_int,
+    pub eid: u32,
+    pub window: Window,
+    pub serial_number: u32,
+    pub ust: u64,
+    pub msc: u64,
+    pub kind: u8,
+    pub mode: u8,
+}
+
+/// Function pointers loaded from `libXext`. The library is never unloaded.
+pub struct Xext {
+    pub XShmQueryExtension: unsafe extern "C" fn(*mut Display) -> Bool,
+    pub XShmAttach: unsafe extern "C" fn(*mut Display, *mut XShmSegmentInfo) -> Bool,
+    pub XShmDetach: unsafe extern "C" fn(*mut Display, *mut XShmSegmentInfo) -> Bool,
+    pub XShmPutImage: unsafe extern "C" fn(
+        *mut Display,
+        Drawable,
+        GC,
+        *mut XImage,
+        c_int,
+        c_int,
+        c_int,
+        c_int,
+        c_uint,
+        c_uint,
+        Bool,
+    ) -> Bool,
+    pub XShapeQueryExtension: unsafe extern "C" fn(*mut Display, *mut c_int, *mut c_int) -> Bool,
+    pub XShapeCombineRectangles: unsafe extern "C" fn(
+        *mut Display,
+        Window,
+        c_int,
+        c_int,
+        c_int,
+        *mut XRectangle,
+        c_int,
+        c_int,
+        c_int,
+    ),
+    pub XShapeCombineMask:
+        unsafe extern "C" fn(*mut Display, Window, c_int, c_int, c_int, Pixmap, c_int),
+}
+
+impl Xext {
+    pub fn open() -> Option<Self> {
+        unsafe {
+            let sym = open_library(&[b"libXext.so.6\0", b"libXext.so\0"])?;
+
+            Some(Self {
+                XShmQueryExtension: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(*mut Display) -> Bool,
+                >(sym(b"XShmQueryExtension\0")?),
+                XShmAttach: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(*mut Display, *mut XShmSegmentInfo) -> Bool,
+                >(sym(b"XShmAttach\0")?),
+                XShmDetach: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(*mut Display, *mut XShmSegmentInfo) -> Bool,
+                >(sym(b"XShmDetach\0")?),
+                XShmPutImage: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(
+                        *mut Display,
+                        Drawable,
+                        GC,
+                        *mut XImage,
+                        c_int,
+                        c_int,
+                        c_int,
+                        c_int,
+                        c_uint,
+                        c_uint,
+                        Bool,
+                    ) -> Bool,
+                >(sym(b"XShmPutImage\0")?),
+                XShapeQueryExtension: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(*mut Display, *mut c_int, *mut c_int) -> Bool,
+                >(sym(b"XShapeQueryExtension\0")?),
+                XShapeCombineRectangles: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(
+                        *mut Display,
+                        Window,
+                        c_int,
+                        c_int,
+                        c_int,
+                        *mut XRectangle,
+                        c_int,
+                        c_int,
+                        c_int,
+                    ),
+                >(sym(b"XShapeCombineRectangles\0")?),
+                XShapeCombineMask: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(*mut Display, Window, c_int, c_int, c_int, Pixmap, c_int),
+                >(sym(b"XShapeCombineMask\0")?),
+            })
+        }
+    }
+}
+
+/// Function pointers loaded from `libXpresent`. The library is never unloaded.
+pub struct Xpresent {
+    pub XPresentQueryExtension:
+        unsafe extern "C" fn(*mut Display, *mut c_int, *mut c_int, *mut c_int) -> Bool,
+    pub XPresentPixmap: unsafe extern "C" fn(
+        *mut Display,
+        Window,
+        Pixmap,
+        u32,
+        XID, // valid: XserverRegion
+        XID, // update: XserverRegion
+        c_int,
+        c_int,
+        XID, // target_crtc: RRCrtc
+        XID, // wait_fence: XSyncFence
+        XID, // idle_fence: XSyncFence
+        u32,
+        u64,
+        u64,
+        u64,
+        *mut c_void, // notifies: *mut XPresentNotify
+        c_int,
+    ),
+    pub XPresentSelectInput: unsafe extern "C" fn(*mut Display, Window, c_uint) -> XID,
+    pub XPresentFreeInput: unsafe extern "C" fn(*mut Display, Window, XID),
+}
+
+impl Xpresent {
+    pub fn open() -> Option<Self> {
+        unsafe {
+            let sym = open_library(&[b"libXpresent.so.1\0", b"libXpresent.so\0"])?;
+
+            Some(Self {
+                XPresentQueryExtension: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(*mut Display, *mut c_int, *mut c_int, *mut c_int) -> Bool,
+                >(sym(b"XPresentQueryExtension\0")?),
+                XPresentPixmap: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(
+                        *mut Display,
+                        Window,
+                        Pixmap,
+                        u32,
+                        XID,
+                        XID,
+                        c_int,
+                        c_int,
+                        XID,
+                        XID,
+                        XID,
+                        u32,
+                        u64,
+                        u64,
+                        u64,
+                        *mut c_void,
+                        c_int,
+                    ),
+                >(sym(b"XPresentPixmap\0")?),
+                XPresentSelectInput: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(*mut Display, Window, c_uint) -> XID,
+                >(sym(b"XPresentSelectInput\0")?),
+                XPresentFreeInput: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(*mut Display, Window, XID),
+                >(sym(b"XPresentFreeInput\0")?),
+            })
+        }
+    }
+}
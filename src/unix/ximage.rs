@@ -0,0 +1,352 @@
+//! Pixel layouts and image transfer shared by the Xlib and XCB backends
+use std::os::raw::c_int;
+use x11_dl::xlib;
+
+use super::super::{Format, Rect, SurfaceError};
+
+/// The size of the fixed part of a `PutImage` request.
+const PUT_IMAGE_HEADER_LEN: usize = 24;
+
+/// The maximum length of a request that doesn't use the BIG-REQUESTS
+/// extension. Longer requests have an extra 4-byte length field.
+const MAX_NORMAL_REQUEST_LEN: usize = 65535 * 4;
+
+/// Set the bits `alpha_mask` of the pixels in `row`, which is a part of a
+/// row of a 32-bit image.
+pub fn fill_alpha(row: &mut [u8], alpha_mask: u32) {
+    for pixel in row.chunks_exact_mut(4) {
+        let value = u32::from_ne_bytes([pixel[0], pixel[1], pixel[2], pixel[3]]);
+        pixel.copy_from_slice(&(value | alpha_mask).to_ne_bytes());
+    }
+}
+
+/// The pixel layout of a window's visual.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VisualLayout {
+    pub depth: u32,
+    pub bits_per_pixel: u32,
+    /// The bit masks of the red, green, and blue channels.
+    pub masks: [u32; 3],
+}
+
+impl VisualLayout {
+    /// Construct a `VisualLayout` from a visual's properties. `bits_per_pixel`
+    /// comes from the pixmap format for `depth`.
+    pub fn new(
+        class: c_int,
+        depth: u32,
+        bits_per_pixel: Option<u32>,
+        masks: [u32; 3],
+    ) -> Result<Self, SurfaceError> {
+        if class != xlib::TrueColor && class != xlib::DirectColor {
+            return Err(unsupported_visual(class, depth));
+        }
+
+        match bits_per_pixel {
+            Some(bits_per_pixel @ 16) | Some(bits_per_pixel @ 32) => Ok(Self {
+                depth,
+                bits_per_pixel,
+                masks,
+            }),
+            _ => Err(unsupported_visual(class, depth)),
+        }
+    }
+
+    /// Get the formats that can be sent without conversion.
+    pub fn native_formats(&self) -> &'static [Format] {
+        match (self.bits_per_pixel, self.masks) {
+            (32, [0xff0000, 0xff00, 0xff]) => &[Format::Argb8888, Format::Xrgb8888],
+            (32, [0xff, 0xff00, 0xff0000]) => &[Format::Abgr8888, Format::Xbgr8888],
+            (32, [0x3ff00000, 0xffc00, 0x3ff]) => &[Format::Argb2101010, Format::Xrgb2101010],
+            (16, [0xf800, 0x7e0, 0x1f]) => &[Format::Rgb565],
+            _ => &[],
+        }
+    }
+
+    /// Get a flag indicating whether the alpha channel of the visual must be
+    /// filled with opaque values when sending an image in `format` to the
+    /// window. This is the case when the visual has an alpha channel, but the
+    /// image shouldn't be translucent, in which case its X channel is
+    /// undefined.
+    ///
+    /// Only applies to the formats in `native_formats`. `PixelConverter`
+    /// handles the others.
+    pub fn fills_alpha(&self, format: Format, opaque: bool) -> bool {
+        let keep_alpha = !opaque && format.has_alpha();
+        self.alpha_mask() != 0 && !keep_alpha && self.native_formats().contains(&format)
+    }
+
+    /// Get the bits not used by the color channels, which are interpreted as
+    /// (premultiplied) alpha by a compositing manager. Returns `0` if the
+    /// visual doesn't have an alpha channel.
+    pub fn alpha_mask(&self) -> u32 {
+        if self.depth == 32 {
+            !(self.masks[0] | self.masks[1] | self.masks[2])
+        } else {
+            0
+        }
+    }
+}
+
+/// Converts `Argb8888` and `Xrgb8888` images to a visual's pixel layout.
+pub struct PixelConverter {
+    bytes_per_pixel: usize,
+    alpha_mask: u32,
+    /// Lookup tables mapping 8-bit red, green, blue, and alpha values to
+    /// the visual's bit fields.
+    luts: Box<[[u32; 256]; 4]>,
+}
+
+impl PixelConverter {
+    pub fn new(visual: VisualLayout) -> Self {
+        let alpha_mask = visual.alpha_mask();
+        let masks = [
+            visual.masks[0],
+            visual.masks[1],
+            visual.masks[2],
+            alpha_mask,
+        ];
+
+        let mut luts = Box::new([[0u32; 256]; 4]);
+        for (lut, &mask) in luts.iter_mut().zip(masks.iter()) {
+            if mask == 0 {
+                continue;
+            }
+            let shift = mask.trailing_zeros();
+            let max = u64::from(mask >> shift);
+            for (i, x) in lut.iter_mut().enumerate() {
+                *x = (((i as u64 * max + 127) / 255) as u32) << shift;
+            }
+        }
+
+        Self {
+            bytes_per_pixel: visual.bits_per_pixel as usize / 8,
+            alpha_mask,
+            luts,
+        }
+    }
+
+    /// Convert the pixels in `rect` from `src` to `dst`. If `keep_alpha` is
+    /// `false`, the alpha channel of `src` is ignored and the output is
+    /// opaque.
+    pub fn convert_rect(
+        &self,
+        src: &[u8],
+        src_stride: usize,
+        dst: &mut [u8],
+        dst_stride: usize,
+        rect: Rect,
+        keep_alpha: bool,
+    ) {
+        let luts = &*self.luts;
+        let [x, y] = [rect.origin[0] as usize, rect.origin[1] as usize];
+        let [width, height] = [rect.extent[0] as usize, rect.extent[1] as usize];
+        let bpp = self.bytes_per_pixel;
+
+        for y in y..y + height {
+            let src_row = &src[y * src_stride + x * 4..][..width * 4];
+            let dst_row = &mut dst[y * dst_stride + x * bpp..][..width * bpp];
+
+            for (s, d) in src_row.chunks_exact(4).zip(dst_row.chunks_exact_mut(bpp)) {
+                let s = u32::from_ne_bytes([s[0], s[1], s[2], s[3]]);
+                let alpha = if keep_alpha {
+                    luts[3][(s >> 24) as usize]
+                } else {
+                    self.alpha_mask
+                };
+                let value = luts[0][(s >> 16 & 0xff) as usize]
+                    | luts[1][(s >> 8 & 0xff) as usize]
+                    | luts[2][(s & 0xff) as usize]
+                    | alpha;
+
+                if bpp == 2 {
+                    d.copy_from_slice(&(value as u16).to_ne_bytes());
+                } else {
+                    d.copy_from_slice(&value.to_ne_bytes());
+                }
+            }
+        }
+    }
+}
+
+/// Get the length of a `PutImage` scanline, which is padded to 32 bits.
+pub fn pad_row_len(row_len: usize) -> usize {
+    (row_len + 3) & !3
+}
+
+/// Split `rect` into pieces, each of which can be sent by a `PutImage` request
+/// not exceeding `max_request_len` bytes. The pieces are horizontal bands
+/// unless a single row doesn't fit in a request.
+pub fn put_image_pieces(
+    rect: Rect,
+    bytes_per_pixel: usize,
+    max_request_len: usize,
+) -> impl Iterator<Item = Rect> {
+    let header_len = if max_request_len > MAX_NORMAL_REQUEST_LEN {
+        PUT_IMAGE_HEADER_LEN + 4
+    } else {
+        PUT_IMAGE_HEADER_LEN
+    };
+    let max_data_len = max_request_len.saturating_sub(header_len);
+    let max_width = ((max_data_len & !3) / bytes_per_pixel).max(1) as u32;
+    let x_end = rect.origin[0] + rect.extent[0];
+    let y_end = rect.origin[1] + rect.extent[1];
+
+    (rect.origin[0]..x_end)
+        .step_by(max_width as usize)
+        .flat_map(move |x| {
+            let width = max_width.min(x_end - x);
+            let row_len = pad_row_len(width as usize * bytes_per_pixel);
+            let max_height = (max_data_len / row_len).max(1) as u32;
+
+            (rect.origin[1]..y_end)
+                .step_by(max_height as usize)
+                .map(move |y| Rect::new([x, y], [width, max_height.min(y_end - y)]))
+        })
+}
+
+fn unsupported_visual(class: c_int, depth: u32) -> SurfaceError {
+    SurfaceError::Platform(format!(
+        "unsupported visual (class = {}, depth = {})",
+        class, depth
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fill_alpha_row() {
+        let mut row: Vec<u8> = [0x00123456u32, 0x80abcdef]
+            .iter()
+            .flat_map(|p| p.to_ne_bytes().to_vec())
+            .collect();
+        fill_alpha(&mut row, 0xff000000);
+
+        let row: Vec<u32> = row
+            .chunks_exact(4)
+            .map(|p| u32::from_ne_bytes([p[0], p[1], p[2], p[3]]))
+            .collect();
+        assert_eq!(row, [0xff123456, 0xffabcdef]);
+    }
+
+    #[test]
+    fn fills_alpha() {
+        let visual = VisualLayout {
+            depth: 32,
+            bits_per_pixel: 32,
+            masks: [0xff0000, 0xff00, 0xff],
+        };
+        assert!(visual.fills_alpha(Format::Xrgb8888, false));
+        assert!(visual.fills_alpha(Format::Argb8888, true));
+        assert!(!visual.fills_alpha(Format::Argb8888, false));
+        // Converted by `PixelConverter`
+        assert!(!visual.fills_alpha(Format::Rgb565, true));
+
+        let visual = VisualLayout {
+            depth: 24,
+            ..visual
+        };
+        assert!(!visual.fills_alpha(Format::Xrgb8888, false));
+    }
+
+    #[test]
+    fn convert_to_rgb555() {
+        let visual = VisualLayout {
+            depth: 15,
+            bits_per_pixel: 16,
+            masks: [0x7c00, 0x3e0, 0x1f],
+        };
+        assert_eq!(visual.native_formats(), &[]);
+        assert_eq!(visual.alpha_mask(), 0);
+
+        let converter = PixelConverter::new(visual);
+        let src: Vec<u8> = [0x80ff0000u32, 0x0000ff00, 0xff0000ff, 0xffffffff]
+            .iter()
+            .flat_map(|p| p.to_ne_bytes().to_vec())
+            .collect();
+        let mut dst = vec![0u8; 8];
+        converter.convert_rect(&src, 16, &mut dst, 8, Rect::new([0, 0], [4, 1]), true);
+
+        let dst: Vec<u16> = dst
+            .chunks_exact(2)
+            .map(|p| u16::from_ne_bytes([p[0], p[1]]))
+            .collect();
+        assert_eq!(dst, [0x7c00, 0x3e0, 0x1f, 0x7fff]);
+    }
+
+    #[test]
+    fn convert_alpha() {
+        // 32-bit ABGR visual
+        let visual = VisualLayout {
+            depth: 32,
+            bits_per_pixel: 32,
+            masks: [0xff, 0xff00, 0xff0000],
+        };
+        assert_eq!(visual.alpha_mask(), 0xff000000);
+
+        let converter = PixelConverter::new(visual);
+        let src = 0x80402010u32.to_ne_bytes();
+        let mut dst = [0u8; 4];
+
+        converter.convert_rect(&src, 4, &mut dst, 4, Rect::new([0, 0], [1, 1]), true);
+        assert_eq!(u32::from_ne_bytes(dst), 0x80102040);
+
+        converter.convert_rect(&src, 4, &mut dst, 4, Rect::new([0, 0], [1, 1]), false);
+        assert_eq!(u32::from_ne_bytes(dst), 0xff102040);
+    }
+
+    #[test]
+    fn pieces() {
+        let rect = Rect::new([3, 10], [100, 7]);
+        let pieces = |max_request_len| -> Vec<_> {
+            put_image_pieces(rect, 4, max_request_len)
+                .map(|r| (r.origin, r.extent))
+                .collect()
+        };
+
+        assert_eq!(pieces(1 << 24), [([3, 10], [100, 7])]);
+
+        // Three rows per request
+        assert_eq!(
+            pieces(PUT_IMAGE_HEADER_LEN + 1200),
+            [
+                ([3, 10], [100, 3]),
+                ([3, 13], [100, 3]),
+                ([3, 16], [100, 1]),
+            ]
+        );
+
+        // A single row doesn't fit in a request
+        let result = pieces(PUT_IMAGE_HEADER_LEN + 240);
+        assert_eq!(result.len(), 2 * 7);
+        assert_eq!(result[0], ([3, 10], [60, 1]));
+        assert_eq!(result[7], ([63, 10], [40, 1]));
+    }
+
+    #[test]
+    fn pieces_big_requests() {
+        let rect = Rect::new([0, 0], [100, 1000]);
+        let request_len = |r: &Rect| {
+            let len = PUT_IMAGE_HEADER_LEN + r.extent[1] as usize * 400;
+            if len > MAX_NORMAL_REQUEST_LEN {
+                len + 4
+            } else {
+                len
+            }
+        };
+
+        // 656 rows would fit if it weren't for the extended length field
+        let max_request_len = PUT_IMAGE_HEADER_LEN + 400 * 656;
+        let result: Vec<_> = put_image_pieces(rect, 4, max_request_len).collect();
+        assert_eq!(result[0].extent, [100, 655]);
+        assert!(result.iter().all(|r| request_len(r) <= max_request_len));
+
+        // Requests that don't need BIG-REQUESTS can fit exactly
+        let max_request_len = PUT_IMAGE_HEADER_LEN + 400 * 655;
+        let result: Vec<_> = put_image_pieces(rect, 4, max_request_len).collect();
+        assert_eq!(result[0].extent, [100, 655]);
+        assert_eq!(request_len(&result[0]), max_request_len);
+    }
+}
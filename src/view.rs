@@ -0,0 +1,346 @@
+//! Typed views of swapchain images
+use std::{
+    fmt,
+    mem::{align_of, size_of},
+    ops::{Deref, DerefMut},
+};
+
+use super::{convert, ImageInfo, Rect};
+
+/// A pixel type of [`ImageViewMut`], i.e., an integer type matching
+/// [`crate::Format::bytes_per_pixel`]. Implemented by `u16` and `u32`.
+///
+/// This trait is sealed and cannot be implemented outside this crate.
+pub trait Pixel: Copy + fmt::Debug + Default + private::Sealed + 'static {}
+
+impl Pixel for u16 {}
+impl Pixel for u32 {}
+
+mod private {
+    pub trait Sealed {}
+    impl Sealed for u16 {}
+    impl Sealed for u32 {}
+}
+
+/// A locked swapchain image returned by [`crate::Surface::lock_image_view`].
+///
+/// Dereferences to the raw contents of the image, which is the slice returned
+/// by [`crate::Surface::lock_image`]. Use [`ImageLock::view_mut`] to access
+/// them as pixels.
+pub struct ImageLock<L> {
+    lock: L,
+    image_info: ImageInfo,
+}
+
+impl<L> ImageLock<L> {
+    pub(crate) fn new(lock: L, image_info: ImageInfo) -> Self {
+        Self { lock, image_info }
+    }
+
+    /// Get the `ImageInfo` describing the image.
+    pub fn image_info(&self) -> &ImageInfo {
+        &self.image_info
+    }
+}
+
+impl<L: DerefMut<Target = [u8]>> ImageLock<L> {
+    /// Get a view of the image's pixels. `P` must be `u32` for 32-bit formats
+    /// and `u16` for 16-bit ones (see [`crate::Format::bytes_per_pixel`]).
+    ///
+    /// Panics if `P` doesn't match the image format or the image isn't
+    /// aligned to `P` (which can only happen if [`crate::Config::align`] is
+    /// smaller than `P`).
+    pub fn view_mut<P: Pixel>(&mut self) -> ImageViewMut<'_, P> {
+        let image_info = self.image_info;
+        ImageViewMut::new(&mut self.lock, &image_info).unwrap_or_else(|| {
+            panic!(
+                "the image ({:?}) can't be viewed as `{}`",
+                image_info.format,
+                std::any::type_name::<P>()
+            )
+        })
+    }
+
+    /// Convert the entire image from straight alpha to pre-multiplied alpha.
+    /// See [`convert::premultiply_in_place`].
+    pub fn premultiply_alpha(&mut self) {
+        let rect = Rect::new([0, 0], self.image_info.extent);
+        convert::premultiply_in_place(&mut self.lock, &self.image_info, rect);
+    }
+
+    /// Convert the entire image from pre-multiplied alpha to straight alpha.
+    /// See [`convert::unpremultiply_in_place`].
+    pub fn unpremultiply_alpha(&mut self) {
+        let rect = Rect::new([0, 0], self.image_info.extent);
+        convert::unpremultiply_in_place(&mut self.lock, &self.image_info, rect);
+    }
+}
+
+impl<L: Deref<Target = [u8]>> Deref for ImageLock<L> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.lock
+    }
+}
+
+impl<L: DerefMut<Target = [u8]>> DerefMut for ImageLock<L> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.lock
+    }
+}
+
+impl<L> fmt::Debug for ImageLock<L> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ImageLock")
+            .field("image_info", &self.image_info)
+            .finish()
+    }
+}
+
+/// A mutable view of a rectangular region of pixels, such as a swapchain
+/// image or a part of it.
+///
+/// Pixels are addressed by `(x, y)` relative to the view's top-left corner,
+/// and each pixel is a native-endian integer of type `P`, laid out as
+/// described by [`crate::Format`].
+pub struct ImageViewMut<'a, P: Pixel = u32> {
+    /// The pixels, starting at the top-left corner. The last row may be
+    /// shorter than `stride`.
+    data: &'a mut [P],
+    width: u32,
+    height: u32,
+    /// The distance between rows, measured in pixels.
+    stride: usize,
+}
+
+impl<'a, P: Pixel> ImageViewMut<'a, P> {
+    /// Construct a view of an image described by `image_info`, such as the
+    /// slice returned by [`crate::Surface::lock_image`].
+    ///
+    /// Returns `None` if `P` doesn't match `image_info.format`, `data` is not
+    /// aligned to `P`, or `data` is too short.
+    pub fn new(data: &'a mut [u8], image_info: &ImageInfo) -> Option<Self> {
+        if image_info.format.bytes_per_pixel() != size_of::<P>()
+            || image_info.stride % size_of::<P>() != 0
+            || data.as_ptr() as usize % align_of::<P>() != 0
+        {
+            return None;
+        }
+
+        let [width, height] = image_info.extent;
+        let stride = image_info.stride / size_of::<P>();
+        let len = if height == 0 {
+            0
+        } else {
+            stride
+                .checked_mul(height as usize - 1)?
+                .checked_add(width as usize)?
+        };
+        if width as usize > stride || len * size_of::<P>() > data.len() {
+            return None;
+        }
+
+        // Safety: `data` is aligned to `P`, and every bit pattern is a valid
+        //         integer
+        let data = unsafe { std::slice::from_raw_parts_mut(data.as_mut_ptr() as *mut P, len) };
+
+        Some(Self {
+            data,
+            width,
+            height,
+            stride,
+        })
+    }
+
+    /// Get the width of the view, measured in pixels.
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// Get the height of the view, measured in pixels.
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Get the distance between the starting points of adjacent rows,
+    /// measured in pixels.
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// Get the pixels of the row `y`. Panics if `y` is out of bounds.
+    pub fn row(&self, y: u32) -> &[P] {
+        assert!(y < self.height, "row index out of bounds");
+        &self.data[y as usize * self.stride..][..self.width as usize]
+    }
+
+    /// Get the pixels of the row `y` mutably. Panics if `y` is out of bounds.
+    pub fn row_mut(&mut self, y: u32) -> &mut [P] {
+        assert!(y < self.height, "row index out of bounds");
+        &mut self.data[y as usize * self.stride..][..self.width as usize]
+    }
+
+    /// Get the pixel at `(x, y)`. Panics if it's out of bounds.
+    pub fn pixel(&self, x: u32, y: u32) -> P {
+        assert!(x < self.width, "column index out of bounds");
+        self.row(y)[x as usize]
+    }
+
+    /// Get a mutable reference to the pixel at `(x, y)`. Panics if it's out of
+    /// bounds.
+    pub fn pixel_mut(&mut self, x: u32, y: u32) -> &mut P {
+        assert!(x < self.width, "column index out of bounds");
+        &mut self.row_mut(y)[x as usize]
+    }
+
+    /// Get an iterator over the rows of the view, from top to bottom.
+    pub fn rows_mut(&mut self) -> impl Iterator<Item = &mut [P]> + '_ {
+        let (width, stride) = (self.width as usize, self.stride);
+        let mut rows_left = self.height;
+        let mut rest: &mut [P] = &mut *self.data;
+
+        std::iter::from_fn(move || {
+            if rows_left == 0 {
+                return None;
+            }
+            rows_left -= 1;
+
+            // The last row might not be padded to `stride`
+            let data = std::mem::take(&mut rest);
+            let (row, tail) = data.split_at_mut(stride.min(data.len()));
+            rest = tail;
+
+            Some(&mut row[..width])
+        })
+    }
+
+    /// Set all pixels of the view to `value`.
+    pub fn fill(&mut self, value: P) {
+        for row in self.rows_mut() {
+            for p in row.iter_mut() {
+                *p = value;
+            }
+        }
+    }
+
+    /// Get a view of the region `rect` of this view. Panics if `rect` doesn't
+    /// fit in this view.
+    pub fn sub_view_mut(&mut self, rect: Rect) -> ImageViewMut<'_, P> {
+        let Rect { origin, extent } = rect;
+        assert!(
+            origin[0]
+                .checked_add(extent[0])
+                .map_or(false, |x| x <= self.width)
+                && origin[1]
+                    .checked_add(extent[1])
+                    .map_or(false, |y| y <= self.height),
+            "rectangle out of bounds"
+        );
+
+        let data_is_empty = extent[0] == 0 || extent[1] == 0;
+        let data: &mut [P] = if data_is_empty {
+            &mut []
+        } else {
+            let start = origin[1] as usize * self.stride + origin[0] as usize;
+            let len = (extent[1] as usize - 1) * self.stride + extent[0] as usize;
+            &mut self.data[start..][..len]
+        };
+
+        ImageViewMut {
+            data,
+            width: extent[0],
+            height: extent[1],
+            // Make `row` work on an empty `data`
+            stride: if data_is_empty { 0 } else { self.stride },
+        }
+    }
+
+    /// Reborrow the view with a shorter lifetime.
+    pub fn reborrow(&mut self) -> ImageViewMut<'_, P> {
+        ImageViewMut {
+            data: &mut *self.data,
+            width: self.width,
+            height: self.height,
+            stride: self.stride,
+        }
+    }
+}
+
+impl<P: Pixel> fmt::Debug for ImageViewMut<'_, P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ImageViewMut")
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("stride", &self.stride)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Format, ImageInfo, Rect};
+    use super::*;
+
+    fn image_info(extent: [u32; 2], stride: usize, format: Format) -> ImageInfo {
+        ImageInfo {
+            extent,
+            stride,
+            format,
+            scale: 1,
+        }
+    }
+
+    #[test]
+    fn rows_and_pixels() {
+        let mut data = vec![0u32; 4 * 3];
+        let bytes =
+            unsafe { std::slice::from_raw_parts_mut(data.as_mut_ptr() as *mut u8, data.len() * 4) };
+        let info = image_info([3, 3], 16, Format::Argb8888);
+
+        let mut view = ImageViewMut::<u32>::new(bytes, &info).unwrap();
+        assert_eq!((view.width(), view.height(), view.stride()), (3, 3, 4));
+
+        *view.pixel_mut(2, 1) = 5;
+        view.row_mut(2)[0] = 7;
+        for (y, row) in view.rows_mut().enumerate() {
+            assert_eq!(row.len(), 3);
+            row[1] = y as u32 + 1;
+        }
+        assert_eq!(view.pixel(2, 1), 5);
+
+        assert_eq!(data, [0, 1, 0, 0, 0, 2, 5, 0, 7, 3, 0, 0]);
+    }
+
+    #[test]
+    fn sub_view() {
+        let mut data = vec![0u16; 4 * 4];
+        let bytes =
+            unsafe { std::slice::from_raw_parts_mut(data.as_mut_ptr() as *mut u8, data.len() * 2) };
+        let info = image_info([4, 4], 8, Format::Rgb565);
+
+        let mut view = ImageViewMut::<u16>::new(bytes, &info).unwrap();
+        let mut sub = view.sub_view_mut(Rect::new([1, 2], [3, 2]));
+        assert_eq!((sub.width(), sub.height()), (3, 2));
+        sub.fill(1);
+        *sub.pixel_mut(0, 1) = 2;
+
+        assert_eq!(data, [0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 0, 2, 1, 1]);
+    }
+
+    #[test]
+    fn mismatch() {
+        let mut data = vec![0u32; 16];
+        let bytes =
+            unsafe { std::slice::from_raw_parts_mut(data.as_mut_ptr() as *mut u8, data.len() * 4) };
+
+        let info = image_info([4, 4], 16, Format::Argb8888);
+        assert!(ImageViewMut::<u16>::new(bytes, &info).is_none());
+        assert!(ImageViewMut::<u32>::new(&mut bytes[..60], &info).is_none());
+        assert!(ImageViewMut::<u32>::new(&mut bytes[1..], &info).is_none());
+
+        // The last row doesn't need padding
+        let info = image_info([3, 4], 16, Format::Argb8888);
+        assert!(ImageViewMut::<u32>::new(&mut bytes[..60], &info).is_some());
+    }
+}
@@ -3,7 +3,7 @@ use owning_ref::OwningRefMut;
 use std::{
     cell::{Cell, RefCell},
     mem::size_of,
-    ops::{Deref, DerefMut},
+    ops::DerefMut,
 };
 use winapi::{
     shared::windef::{HDC, HWND},
@@ -14,7 +14,13 @@ use winapi::{
 };
 use winit::{platform::windows::WindowExtWindows, window::Window};
 
-use super::{align::Align, buffer::Buffer, Config, Format, ImageInfo, NullContextImpl};
+use super::{
+    align::Align,
+    buffer::Buffer,
+    clip_damage,
+    feedback::{FeedbackQueue, PresentationFeedback},
+    Config, Format, ImageInfo, NullContextImpl, Rect, SurfaceError,
+};
 
 #[derive(Debug)]
 pub struct SurfaceImpl {
@@ -22,49 +28,76 @@ pub struct SurfaceImpl {
     image: RefCell<Buffer>,
     image_info: Cell<ImageInfo>,
     scanline_align: Align,
+    feedback: FeedbackQueue,
 }
 
 impl SurfaceImpl {
-    pub(crate) unsafe fn new(window: &Window, _: &NullContextImpl, config: &Config) -> Self {
-        Self {
+    pub(crate) unsafe fn new(
+        window: &Window,
+        _: &NullContextImpl,
+        config: &Config,
+    ) -> Result<Self, SurfaceError> {
+        Ok(Self {
             hwnd: window.hwnd() as _,
             image: RefCell::new(Buffer::from_size_align(1, config.align).unwrap()),
             image_info: Cell::new(ImageInfo::default()),
             scanline_align: Align::new(config.scanline_align).unwrap(),
-        }
+            feedback: FeedbackQueue::new(),
+        })
     }
 
-    pub fn update_surface(&self, extent: [u32; 2], format: Format) {
-        assert_ne!(extent[0], 0);
-        assert_ne!(extent[1], 0);
-        assert!(extent[0] <= <i32>::max_value() as u32);
-        assert!(extent[1] <= <i32>::max_value() as u32);
+    pub fn update_surface(
+        &self,
+        extent: [u32; 2],
+        format: Format,
+        scale: u32,
+    ) -> Result<(), SurfaceError> {
+        if extent[0] == 0 || extent[1] == 0 {
+            return Err(SurfaceError::ZeroExtent);
+        }
+        if extent[0] > i32::MAX as u32 || extent[1] > i32::MAX as u32 {
+            return Err(SurfaceError::ExtentTooLarge);
+        }
 
         use std::convert::TryInto;
         let extent_usize: [usize; 2] = [
-            extent[0].try_into().expect("overflow"),
-            extent[1].try_into().expect("overflow"),
+            extent[0]
+                .try_into()
+                .map_err(|_| SurfaceError::ExtentTooLarge)?,
+            extent[1]
+                .try_into()
+                .map_err(|_| SurfaceError::ExtentTooLarge)?,
         ];
 
         let stride = extent_usize[0]
             .checked_mul(4)
             .and_then(|x| self.scanline_align.align_up(x))
-            .expect("overflow");
+            .ok_or(SurfaceError::ExtentTooLarge)?;
 
-        let size = stride.checked_mul(extent_usize[1]).expect("overflow");
+        let size = stride
+            .checked_mul(extent_usize[1])
+            .ok_or(SurfaceError::ExtentTooLarge)?;
 
         // `stride` is used to derive `BITMAPINFOHEADER::biWidth`, so the derived
         // value must fit in `c_int`
-        let _stride_pixels: std::os::raw::c_int = (stride / 4).try_into().expect("overflow");
+        let _stride_pixels: std::os::raw::c_int = (stride / 4)
+            .try_into()
+            .map_err(|_| SurfaceError::ExtentTooLarge)?;
 
-        let mut image = self.image.borrow_mut();
-        image.resize(size);
+        let mut image = self
+            .image
+            .try_borrow_mut()
+            .map_err(|_| SurfaceError::ImageLocked)?;
+        image.resize(size)?;
 
         self.image_info.set(ImageInfo {
             extent,
             stride,
             format,
+            scale,
         });
+
+        Ok(())
     }
 
     pub fn supported_formats(&self) -> impl Iterator<Item = Format> + '_ {
@@ -83,25 +116,58 @@ impl SurfaceImpl {
         true
     }
 
-    pub fn poll_next_image(&self) -> Option<usize> {
-        Some(0)
+    pub fn honors_scale(&self) -> bool {
+        false
     }
 
-    pub fn lock_image(&self, i: usize) -> impl Deref<Target = [u8]> + DerefMut + '_ {
-        assert_eq!(i, 0);
-        OwningRefMut::new(self.image.borrow_mut()).map_mut(|p| &mut **p)
+    pub fn poll_next_image(&self, filter: &dyn Fn(usize) -> bool) -> Option<usize> {
+        Some(0).filter(|&i| filter(i))
     }
 
-    pub fn present_image(&self, i: usize) {
-        assert_eq!(i, 0);
+    pub fn lock_image(&self, i: usize) -> Result<impl DerefMut<Target = [u8]> + '_, SurfaceError> {
+        if i != 0 {
+            return Err(SurfaceError::BadImageIndex(i));
+        }
+        let image = self
+            .image
+            .try_borrow_mut()
+            .map_err(|_| SurfaceError::ImageLocked)?;
+        Ok(OwningRefMut::new(image).map_mut(|p| &mut **p))
+    }
+
+    pub fn set_destination_size(&self, _size: Option<[u32; 2]>) -> Result<(), SurfaceError> {
+        Err(SurfaceError::BackendUnavailable("`set_destination_size`"))
+    }
+
+    pub fn set_opaque_region(&self, _region: &[Rect]) {}
+
+    pub fn poll_presentation_feedback(&self) -> Option<PresentationFeedback> {
+        self.feedback.pop()
+    }
+
+    pub fn present_image(&self, i: usize, damage: Option<&[Rect]>) -> Result<(), SurfaceError> {
+        if i != 0 {
+            return Err(SurfaceError::BadImageIndex(i));
+        }
 
         let image_info = self.image_info.get();
         let image = self
             .image
             .try_borrow()
-            .expect("the image is currently locked");
+            .map_err(|_| SurfaceError::ImageLocked)?;
 
-        assert_eq!(image_info.format, Format::Argb8888);
+        if image_info.extent[0] == 0 {
+            return Err(SurfaceError::NotInitialized);
+        }
+
+        debug_assert_eq!(image_info.format, Format::Argb8888);
+
+        // TODO: Partial update. For now, the entire image is sent unless the
+        //       damage region is empty.
+        if clip_damage(damage, image_info.extent).next().is_none() {
+            self.feedback.push_estimated(i);
+            return Ok(());
+        }
 
         // The following value works for `Argb8888`.
         // Although the GDI's documentation says that `BI_RGB` ignores the
@@ -124,7 +190,8 @@ impl SurfaceImpl {
         let bitmap_info = &bitmap_info_header as *const BITMAPINFOHEADER as *const BITMAPINFO;
 
         unsafe {
-            let hdc = UniqueDC::new(self.hwnd, GetDC(self.hwnd)).expect("GetDC failed");
+            let hdc = UniqueDC::new(self.hwnd, GetDC(self.hwnd))
+                .ok_or_else(|| SurfaceError::Platform("GetDC failed".to_owned()))?;
 
             StretchDIBits(
                 hdc.hdc(),
@@ -142,6 +209,10 @@ impl SurfaceImpl {
                 SRCCOPY,
             );
         }
+
+        self.feedback.push_estimated(i);
+
+        Ok(())
     }
 }
 